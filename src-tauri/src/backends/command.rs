@@ -0,0 +1,439 @@
+use crate::backends::{parse_options, Backend, BackendRequest, Event, EventStream};
+use crate::settings::{CommandProcess, CommandProtocol, InputMode};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use std::io::Write as _;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{ChildStderr, ChildStdout};
+use tokio_stream::StreamExt;
+
+/// Runs a command with the prompt (as its last argument by default, see `InputMode`), or the
+/// prompt itself as a shell script when no command is given, and streams back whatever it writes
+/// to stdout. Commands using the ndjson protocol write one json event per line instead of plain
+/// text. stderr is logged and never part of the output, `expose_stderr` makes it `$STDERR`, and a
+/// non-zero exit status fails the run.
+pub struct Command;
+
+#[async_trait(?Send)]
+impl Backend for Command {
+    async fn generate(&self, request: BackendRequest) -> EventStream {
+        let mut environment = request.environment.clone();
+        let mut messages_file = None;
+        if request.history.is_some() {
+            // Conversations are handed to the command as a json array of `{ "role", "content" }`,
+            // in a file as they outgrow what fits in an environment variable
+            let messages = serde_json::to_string(&request.messages())
+                .expect("Messages are always valid json");
+            let file = tempfile::Builder::new()
+                .prefix("plock-messages-")
+                .suffix(".json")
+                .tempfile()
+                .and_then(|mut file| file.write_all(messages.as_bytes()).map(|_| file));
+            match file {
+                Ok(file) => {
+                    let path = file.path().to_string_lossy().to_string();
+                    environment.insert("PLOCK_MESSAGES_FILE".to_string(), path);
+                    messages_file = Some(file);
+                }
+                Err(e) => {
+                    let error = format!("Failed to write messages to a temporary file: {}", e);
+                    return Box::pin(tokio_stream::once(Event::Error(error)));
+                }
+            }
+        }
+
+        let config: CommandProcess = match parse_options(request.options) {
+            Ok(config) => config,
+            Err(stream) => return stream,
+        };
+        let final_context = request.prompt;
+
+        let mut command = if config.command.is_empty() {
+            shell(&config.input_mode)
+        } else {
+            let mut command = tokio::process::Command::new(&config.command[0]);
+            command.args(&config.command[1..]);
+            command
+        };
+
+        command
+            .envs(environment)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        // Its own process group, so cancelling also stops anything a script started
+        #[cfg(unix)]
+        command.process_group(0);
+
+        let mut prompt_file = None;
+        match config.input_mode {
+            InputMode::Argv => {
+                command.arg(&final_context);
+            }
+            InputMode::Stdin => {
+                command.stdin(Stdio::piped());
+            }
+            InputMode::File => {
+                // Only readable by the user, and under a name no one can guess to plant something
+                let file = tempfile::Builder::new()
+                    .prefix("plock-prompt-")
+                    .suffix(".txt")
+                    .tempfile()
+                    .and_then(|mut file| file.write_all(final_context.as_bytes()).map(|_| file));
+                match file {
+                    Ok(file) => {
+                        command.arg(file.path());
+                        prompt_file = Some(file);
+                    }
+                    Err(e) => {
+                        let error = format!("Failed to write prompt to a temporary file: {}", e);
+                        return Box::pin(tokio_stream::once(Event::Error(error)));
+                    }
+                }
+            }
+            InputMode::Env => {
+                command.env("PLOCK_PROMPT", &final_context);
+            }
+        }
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                return Box::pin(async_stream::stream! { yield Event::Error(e.to_string()) });
+            }
+        };
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // Written from its own task so a large prompt can't deadlock with the output
+            tokio::spawn(async move {
+                if let Err(e) = stdin.write_all(final_context.as_bytes()).await {
+                    eprintln!("Error writing prompt to stdin: {}", e);
+                }
+            });
+        }
+
+        let stdout = BufReader::new(child.stdout.take().expect("Failed to take stdout of child"));
+        let stderr = BufReader::new(child.stderr.take().expect("Failed to take stderr of child"));
+        // Read stderr on its own so a chatty process can't block stdout, and nothing it writes
+        // ends up in the output
+        let stderr = tokio::spawn(collect_stderr(stderr));
+
+        let mut process_group = ProcessGroup(child.id());
+
+        let mut events = match config.protocol {
+            CommandProtocol::Text => text_events(stdout),
+            CommandProtocol::Ndjson => ndjson_events(stdout),
+        };
+        let program = config.command.first().cloned().unwrap_or("sh".to_string());
+
+        Box::pin(async_stream::stream! {
+            // Deleted along with the stream, however the run ends
+            let _prompt_file = prompt_file;
+            let _messages_file = messages_file;
+
+            while let Some(event) = events.next().await {
+                yield event;
+            }
+
+            let status = child.wait().await;
+            process_group.disarm();
+            let stderr = stderr.await.unwrap_or_default();
+            if config.expose_stderr {
+                yield Event::SetVar { name: "STDERR".to_string(), value: stderr.clone() };
+            }
+
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => {
+                    let last_lines = stderr.lines().rev().take(5).collect::<Vec<&str>>();
+                    let last_lines = last_lines.into_iter().rev().collect::<Vec<&str>>().join("\n");
+                    yield Event::Error(format!("`{}` failed ({})\n{}", program, status, last_lines));
+                }
+                Err(e) => yield Event::Error(format!("Failed to wait for `{}`: {}", program, e)),
+            }
+        })
+    }
+}
+
+/// Kills the command's process group when the run stops before the command finished
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.0 {
+            unsafe {
+                libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+}
+
+/// Runs the prompt itself as a script, however it is handed over
+fn shell(input_mode: &InputMode) -> tokio::process::Command {
+    #[cfg(target_os = "windows")] {
+        let mut command = tokio::process::Command::new("cmd");
+        match input_mode {
+            InputMode::Argv | InputMode::File => command.arg("/C"),
+            InputMode::Stdin => command.arg("/Q"),
+            InputMode::Env => command.args(["/C", "%PLOCK_PROMPT%"]),
+        };
+        command
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        let mut command = tokio::process::Command::new("sh");
+        match input_mode {
+            InputMode::Argv => command.arg("-c"),
+            // sh runs a script from stdin or a file on its own
+            InputMode::Stdin | InputMode::File => &mut command,
+            InputMode::Env => command.args(["-c", "eval \"$PLOCK_PROMPT\""]),
+        };
+        command
+    }
+}
+
+async fn collect_stderr(stderr: BufReader<ChildStderr>) -> String {
+    let mut collected = String::new();
+    let mut lines = stderr.lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        eprintln!("stderr: {}", line);
+        collected.push_str(&line);
+        collected.push('\n');
+    }
+    collected
+}
+
+fn text_events(stdout: BufReader<ChildStdout>) -> EventStream {
+    Box::pin(async_stream::stream! {
+        let mut reader = stdout;
+        let mut buffer = Vec::new();
+
+        loop {
+            buffer.clear();
+            let mut temp_buf = [0; 1024]; // Temporary buffer for each read
+            match reader.read(&mut temp_buf).await {
+                Ok(0) => break, // EOF reached
+                Ok(size) => {
+                    buffer.extend_from_slice(&temp_buf[..size]);
+                    yield Event::Text(String::from_utf8_lossy(&buffer).to_string());
+                },
+                Err(e) => {
+                    eprintln!("Error reading from stdout: {}", e);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// A single line written by a command using the ndjson protocol, e.g. `{"type":"text","delta":"Hi"}`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ProtocolMessage {
+    Text { delta: String },
+    Image { png_base64: String },
+    SetVar { name: String, value: String },
+    Progress { message: String },
+    Error { message: String },
+    Done,
+}
+
+fn ndjson_events(stdout: BufReader<ChildStdout>) -> EventStream {
+    Box::pin(async_stream::stream! {
+        let mut lines = stdout.lines();
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(e) => {
+                    yield Event::Error(format!("Error reading from stdout: {}", e));
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<ProtocolMessage>(&line) {
+                Ok(ProtocolMessage::Text { delta }) => yield Event::Text(delta),
+                Ok(ProtocolMessage::Image { png_base64 }) => {
+                    match STANDARD.decode(png_base64.trim()) {
+                        Ok(png) => yield Event::Image(png),
+                        Err(e) => yield Event::Error(format!("Invalid image: {}", e)),
+                    }
+                }
+                Ok(ProtocolMessage::SetVar { name, value }) => yield Event::SetVar { name, value },
+                Ok(ProtocolMessage::Progress { message }) => yield Event::Progress(message),
+                Ok(ProtocolMessage::Error { message }) => yield Event::Error(message),
+                Ok(ProtocolMessage::Done) => break,
+                Err(e) => yield Event::Error(format!("Invalid protocol message `{}`: {}", line, e)),
+            }
+        }
+    })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::conversation::{Message, Role};
+    use serde_json::{json, Value};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn request(prompt: &str, options: Value) -> BackendRequest {
+        BackendRequest {
+            prompt: prompt.to_string(),
+            history: None,
+            options,
+            environment: Default::default(),
+        }
+    }
+
+    /// The events of `cat`ting `lines` with the ndjson protocol, as text
+    async fn ndjson(lines: &str) -> Vec<String> {
+        let options = json!({"command": ["cat"], "input_mode": "stdin", "protocol": "ndjson"});
+        let events = Command.generate(request(lines, options)).await.collect::<Vec<Event>>().await;
+        events
+            .into_iter()
+            .map(|event| match event {
+                Event::Text(text) => format!("text {}", text),
+                Event::Image(png) => format!("image {:?}", png),
+                Event::SetVar { name, value } => format!("set_var {}={}", name, value),
+                Event::Progress(message) => format!("progress {}", message),
+                Event::Error(e) => format!("error {}", e),
+                Event::Cancelled => "cancelled".to_string(),
+                Event::TimedOut(_) => "timed out".to_string(),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_ndjson_events() {
+        let events = ndjson(concat!(
+            "{\"type\": \"progress\", \"message\": \"thinking\"}\n",
+            "{\"type\": \"text\", \"delta\": \"Hello\"}\n",
+            "\n",
+            "{\"type\": \"image\", \"png_base64\": \"AQID\"}\n",
+            "{\"type\": \"set_var\", \"name\": \"SUMMARY\", \"value\": \"short\"}\n",
+            "{\"type\": \"error\", \"message\": \"went wrong\"}\n",
+        )).await;
+
+        assert_eq!(events, vec![
+            "progress thinking",
+            "text Hello",
+            "image [1, 2, 3]",
+            "set_var SUMMARY=short",
+            "error went wrong",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_ndjson_lines_and_goes_on() {
+        let events = ndjson(concat!(
+            "plain text\n",
+            "{\"type\": \"unknown\"}\n",
+            "{\"type\": \"image\", \"png_base64\": \"not base64!\"}\n",
+            "{\"type\": \"text\", \"delta\": \"still here\"}\n",
+        )).await;
+
+        assert_eq!(events.len(), 4);
+        assert!(events[0].starts_with("error Invalid protocol message `plain text`"));
+        assert!(events[1].starts_with("error Invalid protocol message"));
+        assert!(events[2].starts_with("error Invalid image"));
+        assert_eq!(events[3], "text still here");
+    }
+
+    #[tokio::test]
+    async fn stops_reading_ndjson_at_done() {
+        let events = ndjson(concat!(
+            "{\"type\": \"text\", \"delta\": \"before\"}\n",
+            "{\"type\": \"done\"}\n",
+            "{\"type\": \"text\", \"delta\": \"after\"}\n",
+        )).await;
+
+        assert_eq!(events, vec!["text before"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn kills_what_the_command_started_when_the_run_stops_early() {
+        // Prints the pid of a process it starts, which would outlive the shell
+        let options = json!({"command": ["sh", "-c", "sleep 30 & echo $!; wait"]});
+        let mut stream = Command.generate(request("", options)).await;
+        let Some(Event::Text(pid)) = stream.next().await else {
+            panic!("The command didn't print the pid");
+        };
+        let stat = format!("/proc/{}/stat", pid.trim());
+        // Gone, or a zombie waiting for init
+        let is_running = || std::fs::read_to_string(&stat).is_ok_and(|stat| !stat.contains(") Z "));
+        assert!(is_running());
+
+        drop(stream);
+
+        for _ in 0..50 {
+            if !is_running() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("`sleep` is still running");
+    }
+
+    #[tokio::test]
+    async fn removes_the_prompt_file_when_the_run_stops_early() {
+        // Prints the path it was handed and keeps running
+        let options = json!({"command": ["sh", "-c", "echo \"$0\"; sleep 10"], "input_mode": "file"});
+        let mut stream = Command.generate(request("secret", options)).await;
+
+        let Some(Event::Text(path)) = stream.next().await else {
+            panic!("The command didn't print the path");
+        };
+        let path = PathBuf::from(path.trim());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "secret");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        drop(stream);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn hands_the_conversation_over_in_a_file() {
+        // A long conversation, which wouldn't fit in an environment variable
+        let answer = "a".repeat(200_000);
+        let options = json!({"command": ["sh", "-c", "cat \"$PLOCK_MESSAGES_FILE\""], "input_mode": "env"});
+        let request = BackendRequest {
+            history: Some(vec![
+                Message::new(Role::User, "first".to_string()),
+                Message::new(Role::Assistant, answer.clone()),
+            ]),
+            ..request("second", options)
+        };
+
+        let mut output = String::new();
+        let mut events = Command.generate(request).await;
+        while let Some(event) = events.next().await {
+            let Event::Text(text) = event else {
+                panic!("The command failed");
+            };
+            output.push_str(&text);
+        }
+
+        let messages: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(messages, json!([
+            {"role": "user", "content": "first"},
+            {"role": "assistant", "content": answer},
+            {"role": "user", "content": "second"},
+        ]));
+    }
+}
@@ -0,0 +1,43 @@
+use crate::backends::{parse_options, Backend, BackendRequest, Event, EventStream};
+use async_trait::async_trait;
+use serde::Deserialize;
+
+/// Deterministic backend for trying out triggers without a model, it streams back `response`
+/// (or the prompt itself) in chunks of `chunk_size` characters.
+pub struct Mock;
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct MockConfig {
+    response: Option<String>,
+    chunk_size: usize,
+}
+
+impl Default for MockConfig {
+    fn default() -> Self {
+        Self {
+            response: None,
+            chunk_size: 4,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Mock {
+    async fn generate(&self, request: BackendRequest) -> EventStream {
+        let config: MockConfig = match parse_options(request.options) {
+            Ok(config) => config,
+            Err(stream) => return stream,
+        };
+
+        let response = config.response.unwrap_or(request.prompt);
+        let chunks = response
+            .chars()
+            .collect::<Vec<char>>()
+            .chunks(config.chunk_size.max(1))
+            .map(|chunk| Event::Text(String::from_iter(chunk)))
+            .collect::<Vec<Event>>();
+
+        Box::pin(tokio_stream::iter(chunks))
+    }
+}
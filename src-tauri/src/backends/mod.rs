@@ -0,0 +1,98 @@
+use crate::conversation::{Message, Role};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_stream::Stream;
+
+mod command;
+mod mock;
+mod ollama;
+mod openai;
+
+lazy_static! {
+    pub static ref BACKENDS: RwLock<Registry> = RwLock::new(Registry::default());
+}
+
+pub enum Event {
+    Text(String),
+    /// A png image, e.g. for `write_image_to_screen`
+    Image(Vec<u8>),
+    SetVar { name: String, value: String },
+    Progress(String),
+    /// The run failed, no further steps are taken
+    Error(String),
+    Cancelled,
+    TimedOut(Duration),
+}
+
+pub type EventStream = Pin<Box<dyn Stream<Item=Event>>>;
+
+pub struct BackendRequest {
+    /// The fully rendered prompt
+    pub prompt: String,
+    /// Earlier turns when the trigger is part of a conversation
+    pub history: Option<Vec<Message>>,
+    /// The backend specific configuration of the process, e.g. the model for ollama
+    pub options: Value,
+    /// Variables made available to the backend, commands receive them as environment variables
+    pub environment: HashMap<String, String>,
+}
+
+impl BackendRequest {
+    /// A variable of the run, or of plock's own environment when the run doesn't have it
+    pub fn variable(&self, name: &str) -> Option<String> {
+        self.environment.get(name).cloned().or_else(|| env::var(name).ok())
+    }
+
+    /// The whole conversation including the new prompt, or just the prompt outside of a conversation
+    pub fn messages(&self) -> Vec<Message> {
+        let mut messages = self.history.clone().unwrap_or_default();
+        messages.push(Message::new(Role::User, self.prompt.clone()));
+        messages
+    }
+}
+
+#[async_trait(?Send)]
+pub trait Backend: Send + Sync {
+    async fn generate(&self, request: BackendRequest) -> EventStream;
+}
+
+pub struct Registry {
+    backends: HashMap<String, Arc<dyn Backend>>,
+}
+
+impl Registry {
+    pub fn register(&mut self, name: &str, backend: impl Backend + 'static) -> &mut Self {
+        self.backends.insert(name.to_string(), Arc::new(backend));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn Backend>> {
+        self.backends.get(name).cloned()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self { backends: HashMap::new() };
+        registry
+            .register("ollama", ollama::Ollama)
+            .register("command", command::Command)
+            .register("openai", openai::OpenAi)
+            .register("mock", mock::Mock);
+        registry
+    }
+}
+
+/// Parses the process options into the backend's config, or returns a stream holding the error
+fn parse_options<T: serde::de::DeserializeOwned>(options: Value) -> Result<T, EventStream> {
+    serde_json::from_value(options).map_err(|e| {
+        let error = format!("Invalid process options: {}", e);
+        Box::pin(tokio_stream::once(Event::Error(error))) as EventStream
+    })
+}
@@ -0,0 +1,141 @@
+use crate::backends::{parse_options, Backend, BackendRequest, Event, EventStream};
+use crate::conversation::{Message, Role};
+use crate::settings::{Ollama as OllamaConfig, OllamaFormat, OllamaOptions};
+use async_trait::async_trait;
+use ollama_rs::generation::chat::request::ChatMessageRequest;
+use ollama_rs::generation::chat::{ChatMessage, MessageRole};
+use ollama_rs::generation::completion::request::GenerationRequest;
+use ollama_rs::generation::options::GenerationOptions;
+use ollama_rs::generation::parameters::{FormatType, KeepAlive, TimeUnit};
+use tokio_stream::StreamExt;
+
+pub struct Ollama;
+
+#[async_trait(?Send)]
+impl Backend for Ollama {
+    async fn generate(&self, request: BackendRequest) -> EventStream {
+        let messages = request.history.is_some().then(|| request.messages());
+        let config: OllamaConfig = match parse_options(request.options.clone()) {
+            Ok(config) => config,
+            Err(stream) => return stream,
+        };
+
+        let model = config.model.clone().unwrap_or_else(|| {
+            request.variable("OLLAMA_MODEL").unwrap_or("openhermes2.5-mistral".to_string())
+        });
+
+        if let Some(messages) = messages {
+            return chat(config, model, messages);
+        }
+
+        let mut generation_request = GenerationRequest::new(
+            model, request.prompt,
+        ).options(generation_options(&config.options));
+        if let Some(system) = config.system.clone() {
+            generation_request = generation_request.system(system);
+        }
+        if let Some(keep_alive) = config.keep_alive.as_deref() {
+            match parse_keep_alive(keep_alive) {
+                Some(keep_alive) => generation_request = generation_request.keep_alive(keep_alive),
+                None => eprintln!("Ignoring invalid keep_alive: {}", keep_alive),
+            }
+        }
+        if let Some(OllamaFormat::Json) = config.format {
+            generation_request = generation_request.format(FormatType::Json);
+        }
+
+        Box::pin(async_stream::stream! {
+            let ollama = ollama_rs::Ollama::new(config.host, config.port);
+            let mut stream = match ollama.generate_stream(generation_request).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    yield Event::Error(format!("Failed to reach ollama at {}: {}", ollama.uri(), e));
+                    return;
+                }
+            };
+
+            while let Some(Ok(res)) = stream.next().await {
+                for out in res.into_iter() {
+                    yield Event::Text(out.response);
+                }
+            }
+        })
+    }
+}
+
+/// Continues a conversation through the chat api, which ollama-rs doesn't support `keep_alive` for yet
+fn chat(config: OllamaConfig, model: String, messages: Vec<Message>) -> EventStream {
+    let mut chat_messages = Vec::new();
+    if let Some(system) = config.system.clone() {
+        chat_messages.push(ChatMessage::system(system));
+    }
+    chat_messages.extend(messages.into_iter().map(|message| {
+        let role = match message.role {
+            Role::System => MessageRole::System,
+            Role::User => MessageRole::User,
+            Role::Assistant => MessageRole::Assistant,
+        };
+        ChatMessage::new(role, message.content)
+    }));
+
+    let mut chat_request = ChatMessageRequest::new(model, chat_messages)
+        .options(generation_options(&config.options));
+    if let Some(OllamaFormat::Json) = config.format {
+        chat_request = chat_request.format(FormatType::Json);
+    }
+
+    Box::pin(async_stream::stream! {
+        let ollama = ollama_rs::Ollama::new(config.host, config.port);
+        let mut stream = match ollama.send_chat_messages_stream(chat_request).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                yield Event::Error(format!("Failed to reach ollama at {}: {}", ollama.uri(), e));
+                return;
+            }
+        };
+
+        while let Some(Ok(res)) = stream.next().await {
+            if let Some(message) = res.message {
+                yield Event::Text(message.content);
+            }
+        }
+    })
+}
+
+fn generation_options(options: &OllamaOptions) -> GenerationOptions {
+    let mut generation_options = GenerationOptions::default();
+    if let Some(temperature) = options.temperature {
+        generation_options = generation_options.temperature(temperature);
+    }
+    if let Some(num_ctx) = options.num_ctx {
+        generation_options = generation_options.num_ctx(num_ctx);
+    }
+    if let Some(top_p) = options.top_p {
+        generation_options = generation_options.top_p(top_p);
+    }
+    if let Some(stop) = options.stop.clone() {
+        generation_options = generation_options.stop(stop);
+    }
+    generation_options
+}
+
+/// Accepts the same values as the ollama api: "-1", "0" or a duration like "30s", "10m", "1h"
+fn parse_keep_alive(keep_alive: &str) -> Option<KeepAlive> {
+    let keep_alive = keep_alive.trim();
+    match keep_alive {
+        "-1" => Some(KeepAlive::Indefinitely),
+        "0" => Some(KeepAlive::UnloadOnCompletion),
+        _ => {
+            let (time, unit) = keep_alive.split_at(
+                keep_alive.find(|c: char| !c.is_ascii_digit())?
+            );
+            let unit = match unit {
+                "s" => TimeUnit::Seconds,
+                "m" => TimeUnit::Minutes,
+                "h" | "hr" => TimeUnit::Hours,
+                _ => return None,
+            };
+            Some(KeepAlive::Until { time: time.parse().ok()?, unit })
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::backends::{parse_options, Backend, BackendRequest, Event, EventStream};
+use crate::conversation::Message;
+use crate::settings::OpenAi as OpenAiConfig;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use tokio_stream::StreamExt;
+
+/// Streams a completion from any server speaking the OpenAI `/v1/chat/completions` protocol
+/// (OpenAI, llama.cpp, vLLM, LM Studio, ...), yielding each content delta as it arrives.
+pub struct OpenAi;
+
+#[async_trait(?Send)]
+impl Backend for OpenAi {
+    async fn generate(&self, request: BackendRequest) -> EventStream {
+        let messages = request.messages();
+        let config: OpenAiConfig = match parse_options(request.options.clone()) {
+            Ok(config) => config,
+            Err(stream) => return stream,
+        };
+        let api_key = config.api_key_env.as_ref().and_then(|name| request.variable(name));
+        stream_chat_completion(config, messages, api_key)
+    }
+}
+
+fn stream_chat_completion(
+    config: OpenAiConfig, messages: Vec<Message>, api_key: Option<String>,
+) -> EventStream {
+    let url = format!("{}/chat/completions", config.base_url.trim_end_matches('/'));
+
+    let mut body = json!({
+        "model": config.model,
+        "messages": messages,
+        "stream": true,
+    });
+    if let Some(temperature) = config.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(max_tokens) = config.max_tokens {
+        body["max_tokens"] = json!(max_tokens);
+    }
+
+    Box::pin(async_stream::stream! {
+        let mut request = reqwest::Client::new().post(&url).json(&body);
+        if let Some(key) = api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response = match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(response) => response,
+            Err(e) => {
+                yield Event::Error(format!("Failed to reach {}: {}", url, e));
+                return;
+            }
+        };
+
+        let mut bytes = response.bytes_stream();
+        let mut pending = Vec::new();
+
+        while let Some(chunk) = bytes.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Event::Error(format!("Error reading from {}: {}", url, e));
+                    break;
+                }
+            };
+            pending.extend_from_slice(&chunk);
+
+            // SSE events are newline delimited, but a chunk can end mid-line
+            while let Some(newline) = pending.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = pending.drain(..=newline).collect();
+                match parse_sse_line(&String::from_utf8_lossy(&line)) {
+                    SseLine::Delta(delta) => yield Event::Text(delta),
+                    SseLine::Error(e) => {
+                        yield Event::Error(format!("{} failed: {}", url, e));
+                        return;
+                    }
+                    SseLine::Done => return,
+                    SseLine::Skip => {}
+                }
+            }
+        }
+    })
+}
+
+enum SseLine {
+    Delta(String),
+    /// The server failed after it started streaming, e.g. `{"error": {"message": "..."}}`
+    Error(String),
+    Done,
+    Skip,
+}
+
+fn parse_sse_line(line: &str) -> SseLine {
+    let Some(data) = line.trim().strip_prefix("data:") else {
+        // Blank keep-alive lines, comments, `event:` and `id:` fields
+        return SseLine::Skip;
+    };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return SseLine::Done;
+    }
+
+    match serde_json::from_str::<Value>(data) {
+        Ok(event) if !event["error"].is_null() => {
+            let error = &event["error"];
+            let message = error["message"].as_str().or(error.as_str()).map(str::to_string);
+            SseLine::Error(message.unwrap_or(error.to_string()))
+        }
+        Ok(event) => match event["choices"][0]["delta"]["content"].as_str() {
+            Some(content) if !content.is_empty() => SseLine::Delta(content.to_string()),
+            _ => SseLine::Skip,
+        },
+        Err(e) => {
+            eprintln!("Failed to parse event `{}`: {}", data, e);
+            SseLine::Skip
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers a single request with `chunks` as a stream of server-sent events, written one at a time
+    async fn serve(chunks: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            for chunk in chunks {
+                socket.write_all(chunk.as_bytes()).await.unwrap();
+                socket.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        format!("http://{}/v1", address)
+    }
+
+    async fn generate(base_url: String) -> Vec<Event> {
+        let config = OpenAiConfig { base_url, ..Default::default() };
+        stream_chat_completion(config, Vec::new(), None).collect().await
+    }
+
+    #[tokio::test]
+    async fn streams_deltas_split_across_chunks_until_done() {
+        let base_url = serve(vec![
+            ": keep-alive\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choi",
+            "ces\":[{\"delta\":{\"content\":\"lo\"}}]}\n\n",
+            "data: [DONE]\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"after\"}}]}\n\n",
+        ]).await;
+
+        let text = generate(base_url)
+            .await
+            .into_iter()
+            .map(|event| match event {
+                Event::Text(text) => text,
+                _ => panic!("Expected only text"),
+            })
+            .collect::<String>();
+
+        assert_eq!(text, "Hello");
+    }
+
+    #[tokio::test]
+    async fn fails_on_an_error_event() {
+        let base_url = serve(vec![
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hi\"}}]}\n\n",
+            "data: {\"error\":{\"message\":\"model overloaded\"}}\n\n",
+        ]).await;
+
+        let events = generate(base_url).await;
+
+        assert!(matches!(&events[0], Event::Text(text) if text == "Hi"));
+        assert!(matches!(&events[1], Event::Error(e) if e.contains("model overloaded")));
+        assert_eq!(events.len(), 2);
+    }
+}
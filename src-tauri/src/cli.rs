@@ -0,0 +1,293 @@
+//! Runs triggers from a terminal, without the app, a desktop session or any shortcuts:
+//!
+//! ```text
+//! echo "some text" | plock run --trigger rewrite --selection - --clipboard notes.txt
+//! ```
+//!
+//! Whatever a trigger would type, paste or show is written to stdout instead. `plock history` lists
+//! the runs of the app and of `plock run`.
+
+use crate::context::RunContext;
+use crate::history::{self, history_path, Entry, History, Query};
+use crate::pipeline::{OutputSink, Pipeline};
+use crate::settings::{default_settings_path, load_settings_file, Reference};
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use tauri::api::path::app_local_data_dir;
+use tauri::Config;
+use tokio::runtime::Runtime;
+
+const USAGE: &str = "\
+Usage: plock run --trigger <name> [options]
+
+Runs a trigger of the settings, writing its output to stdout.
+
+Options:
+    --trigger <name>      The name or index of the trigger to run
+    --selection <file>    Where $SELECTION comes from, `-` for stdin
+    --clipboard <file>    Where $CLIPBOARD comes from, `-` for stdin
+    --settings <file>     The settings to use instead of those of the app";
+
+const HISTORY_USAGE: &str = "\
+Usage: plock history [options]
+
+Lists the latest runs, newest first.
+
+Options:
+    --trigger <name>      Only runs of this trigger, `#<index>` for one without a name
+    --search <text>       Only runs whose prompt, selection or output contains this
+    --failed              Only runs that failed or were cancelled
+    --limit <n>           How many runs to list, 20 when not set
+    --json                Writes the runs as json lines, with everything recorded";
+
+struct Options {
+    trigger: Reference,
+    selection: Option<PathBuf>,
+    clipboard: Option<PathBuf>,
+    settings: Option<PathBuf>,
+}
+
+/// Runs `plock run` with the arguments after `run`, returning the exit code
+pub fn run(args: &[String], config: &Config) -> i32 {
+    let options = match parse(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return 2;
+        }
+    };
+
+    match run_trigger(options, config) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+/// The options in `args`, `None` when asked for help
+fn parse(args: &[String]) -> Result<Option<Options>, String> {
+    let mut trigger = None;
+    let mut selection = None;
+    let mut clipboard = None;
+    let mut settings = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Ok(None);
+        }
+        let value = args.next().ok_or(format!("`{}` needs a value", arg))?;
+        match arg.as_str() {
+            "--trigger" => {
+                trigger = Some(match value.parse::<usize>() {
+                    Ok(index) => Reference::Index(index),
+                    Err(_) => Reference::Name(value.clone()),
+                });
+            }
+            "--selection" => selection = Some(PathBuf::from(value)),
+            "--clipboard" => clipboard = Some(PathBuf::from(value)),
+            "--settings" => settings = Some(PathBuf::from(value)),
+            _ => return Err(format!("Unknown option `{}`", arg)),
+        }
+    }
+
+    let stdin = PathBuf::from("-");
+    if selection.as_ref() == Some(&stdin) && clipboard.as_ref() == Some(&stdin) {
+        return Err("Only one of `--selection` and `--clipboard` can read stdin".to_string());
+    }
+    let trigger = trigger.ok_or("`--trigger` is missing")?;
+    Ok(Some(Options { trigger, selection, clipboard, settings }))
+}
+
+fn run_trigger(options: Options, config: &Config) -> Result<(), String> {
+    let path = match options.settings {
+        Some(path) => path,
+        None => default_settings_path(config)?,
+    };
+    let (settings, warnings) = load_settings_file(&path)?;
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+
+    let index = settings
+        .trigger_index(&options.trigger)
+        .ok_or(format!("There is no trigger {}", options.trigger))?;
+
+    let mut context = RunContext::new(settings.environment.clone());
+    context.set_builtin("SELECTION", read_input(options.selection)?);
+    context.set_builtin("CLIPBOARD", read_input(options.clipboard)?);
+
+    // Stopping is left to Ctrl+C
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut stdout = Stdout { wrote_text: false };
+    let mut pipeline = Pipeline::new(&settings, &mut stdout, cancel);
+    if let Some(dir) = app_local_data_dir(config) {
+        pipeline = pipeline.with_history(History::new(&dir, settings.history.clone()), None);
+    }
+    let rt = Runtime::new().map_err(|e| format!("Failed to start the runtime: {}", e))?;
+    rt.block_on(pipeline.run(index, &mut context, false))?;
+
+    // Keeps the shell prompt off the last line of the output
+    if stdout.wrote_text && io::stdout().is_terminal() {
+        write_output(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Runs `plock history` with the arguments after `history`, returning the exit code
+pub fn history(args: &[String], config: &Config) -> i32 {
+    let (query, json) = match parse_history(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", HISTORY_USAGE);
+            return 0;
+        }
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, HISTORY_USAGE);
+            return 2;
+        }
+    };
+
+    match list_history(&query, json, config) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+/// The query in `args` and whether to write json, `None` when asked for help
+fn parse_history(args: &[String]) -> Result<Option<(Query, bool)>, String> {
+    let mut query = Query { limit: Some(20), ..Query::default() };
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--failed" => query.failed = true,
+            "--json" => json = true,
+            "--trigger" | "--search" | "--limit" => {
+                let value = args.next().ok_or(format!("`{}` needs a value", arg))?.clone();
+                match arg.as_str() {
+                    "--trigger" => query.trigger = Some(value),
+                    "--search" => query.search = Some(value),
+                    _ => {
+                        let limit = value.parse().map_err(|_| format!("`{}` isn't a number", value))?;
+                        query.limit = Some(limit);
+                    }
+                }
+            }
+            _ => return Err(format!("Unknown option `{}`", arg)),
+        }
+    }
+    Ok(Some((query, json)))
+}
+
+fn list_history(query: &Query, json: bool, config: &Config) -> Result<(), String> {
+    let dir = app_local_data_dir(config).ok_or("Failed to get local data dir".to_string())?;
+    let entries = history::query(&history_path(&dir), query)?;
+
+    let mut lines = String::new();
+    for entry in entries {
+        if json {
+            lines.push_str(&serde_json::to_string(&entry).map_err(|e| e.to_string())?);
+            lines.push('\n');
+        } else {
+            lines.push_str(&describe(&entry));
+        }
+    }
+    write_output(lines.as_bytes())
+}
+
+/// A run as a line about how it went, followed by the start of its output
+fn describe(entry: &Entry) -> String {
+    let status = match (&entry.error, entry.cancelled) {
+        (_, true) => "cancelled".to_string(),
+        (Some(error), _) => format!("failed: {}", error.lines().next().unwrap_or_default()),
+        (None, false) => "ok".to_string(),
+    };
+    let output = entry.output.trim().lines().next().unwrap_or_default();
+    let output = match output.char_indices().nth(80) {
+        Some((end, _)) => format!("{}...", &output[..end]),
+        None => output.to_string(),
+    };
+    let mut description = format!(
+        "{} ago  {} ({})  {:.1}s  {}\n",
+        ago(history::now().saturating_sub(entry.started_at)),
+        entry.trigger,
+        entry.process,
+        entry.duration_ms as f64 / 1000.0,
+        status,
+    );
+    if !output.is_empty() {
+        description.push_str(&format!("    {}\n", output));
+    }
+    description
+}
+
+/// How long ago something was, roughly, from the seconds since
+fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Writes what a trigger would type or paste to stdout
+struct Stdout {
+    wrote_text: bool,
+}
+
+impl OutputSink for Stdout {
+    fn stream_text(&mut self, text: &str) -> Result<(), String> {
+        self.write_text(text)
+    }
+
+    fn write_text(&mut self, text: &str) -> Result<(), String> {
+        self.wrote_text = true;
+        write_output(text.as_bytes())
+    }
+
+    fn write_image(&mut self, png: &[u8]) -> Result<(), String> {
+        if io::stdout().is_terminal() {
+            return Err("Not writing an image to the terminal, redirect stdout to a file".to_string());
+        }
+        write_output(png)
+    }
+}
+
+/// The contents of `path`, stdin when it's `-`, and nothing when there's no path
+fn read_input(path: Option<PathBuf>) -> Result<String, String> {
+    match path {
+        None => Ok(String::new()),
+        Some(path) if path.as_os_str() == "-" => {
+            let mut input = String::new();
+            io::stdin()
+                .read_to_string(&mut input)
+                .map_err(|e| format!("Failed to read stdin: {}", e))?;
+            Ok(input)
+        }
+        Some(path) => fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+fn write_output(bytes: &[u8]) -> Result<(), String> {
+    let mut stdout = io::stdout().lock();
+    stdout
+        .write_all(bytes)
+        .and_then(|_| stdout.flush())
+        .map_err(|e| format!("Failed to write output: {}", e))
+}
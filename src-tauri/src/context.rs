@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+
+/// The variables of a single run of a trigger, including any triggers it chains into.
+///
+/// Nothing is written to plock's own environment or back into the settings, so a selection or a
+/// stored output never outlives the run that produced it.
+#[derive(Clone, Default)]
+pub struct RunContext {
+    /// `SELECTION`, `CLIPBOARD` and `OCR`, captured when the run starts
+    builtins: HashMap<String, String>,
+    /// The `environment` of the settings
+    constants: HashMap<String, String>,
+    /// Stored by `store_as_env_var` steps and `set_var` events
+    outputs: HashMap<String, String>,
+}
+
+impl RunContext {
+    pub fn new(constants: HashMap<String, String>) -> Self {
+        Self { constants, ..Self::default() }
+    }
+
+    pub fn set_builtin(&mut self, name: &str, value: String) {
+        self.builtins.insert(name.to_string(), value);
+    }
+
+    pub fn set_output(&mut self, name: String, value: String) {
+        self.outputs.insert(name, value);
+    }
+
+    /// Outputs win over built-ins, which win over constants
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.outputs
+            .get(name)
+            .or_else(|| self.builtins.get(name))
+            .or_else(|| self.constants.get(name))
+            .map(String::as_str)
+    }
+
+    /// The variables handed to backends, which command processes receive as environment variables
+    /// on top of plock's own environment. Those are the constants, plus the variables in `export`
+    /// with the same precedence as `get`. Selections and outputs can be large or private, so they're
+    /// only handed to processes that ask for them.
+    pub fn exports(&self, export: &[String]) -> HashMap<String, String> {
+        let mut exports = self.constants.clone();
+        for name in export {
+            if let Some(value) = self.get(name) {
+                exports.insert(name.clone(), value.to_string());
+            }
+        }
+        exports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exports_constants_and_only_the_listed_variables() {
+        let mut context = RunContext::new(HashMap::from([("API".to_string(), "key".to_string())]));
+        context.set_builtin("SELECTION", "selected".to_string());
+        context.set_builtin("CLIPBOARD", "private".to_string());
+        context.set_output("SUMMARY".to_string(), "short".to_string());
+
+        let exports = context.exports(&["SELECTION".to_string(), "MISSING".to_string()]);
+
+        assert_eq!(exports, HashMap::from([
+            ("API".to_string(), "key".to_string()),
+            ("SELECTION".to_string(), "selected".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn exports_outputs_over_builtins_over_constants() {
+        let mut context = RunContext::new(HashMap::from([("NAME".to_string(), "constant".to_string())]));
+        context.set_builtin("NAME", "builtin".to_string());
+        assert_eq!(context.exports(&["NAME".to_string()])["NAME"], "builtin");
+
+        context.set_output("NAME".to_string(), "output".to_string());
+        assert_eq!(context.exports(&["NAME".to_string()])["NAME"], "output");
+        assert_eq!(context.exports(&[])["NAME"], "constant");
+    }
+}
@@ -0,0 +1,51 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<String, Vec<Message>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: Role, content: String) -> Self {
+        Self { role, content }
+    }
+}
+
+/// Previous turns of the conversation, empty if the session hasn't been used yet
+pub fn history(session: &str) -> Vec<Message> {
+    SESSIONS.lock().unwrap().get(session).cloned().unwrap_or_default()
+}
+
+pub fn record(session: &str, prompt: String, response: String) {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let messages = sessions.entry(session.to_string()).or_default();
+    messages.push(Message::new(Role::User, prompt));
+    messages.push(Message::new(Role::Assistant, response));
+}
+
+pub fn reset(session: &str) {
+    SESSIONS.lock().unwrap().remove(session);
+}
+
+/// Copies the history of `from` into `into`, so both can continue independently
+pub fn fork(from: &str, into: &str) {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let messages = sessions.get(from).cloned().unwrap_or_default();
+    sessions.insert(into.to_string(), messages);
+}
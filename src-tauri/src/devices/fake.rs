@@ -0,0 +1,140 @@
+//! A clipboard and keyboard that only pretend, and record what they were asked to do
+
+use super::{Clipboard, Keyboard};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// What a fake was asked to do, in order
+#[derive(Clone, Debug, PartialEq)]
+pub enum Recorded {
+    Copy,
+    Paste,
+    Backspace,
+    Type(String),
+    SetText(String),
+    SetImage(Vec<u8>),
+}
+
+/// The focused app, clipboard and record the fakes share
+#[derive(Default)]
+struct Desktop {
+    /// What's selected in the focused app
+    selection: String,
+    clipboard: String,
+    /// Whether the clipboard holds something other than text, like an image
+    unreadable: bool,
+    recorded: Vec<Recorded>,
+}
+
+/// The clipboard and keyboard of a pretend desktop. Clones share the same desktop, so one can be
+/// handed to the pipeline and the other checked after.
+#[derive(Clone, Default)]
+pub struct Fake {
+    desktop: Arc<Mutex<Desktop>>,
+}
+
+impl Fake {
+    pub fn new(selection: &str, clipboard: &str) -> Self {
+        let desktop = Desktop {
+            selection: selection.to_string(),
+            clipboard: clipboard.to_string(),
+            unreadable: false,
+            recorded: Vec::new(),
+        };
+        Self { desktop: Arc::new(Mutex::new(desktop)) }
+    }
+
+    /// Reading text from the clipboard fails, as when it holds an image, until text is put on it
+    pub fn with_unreadable_clipboard(self) -> Self {
+        self.lock().unreadable = true;
+        self
+    }
+
+    pub fn recorded(&self) -> Vec<Recorded> {
+        self.lock().recorded.clone()
+    }
+
+    /// Everything typed and pasted into the focused app, as text
+    pub fn written(&self) -> String {
+        let desktop = self.lock();
+        let mut clipboard = String::new();
+        let mut written = String::new();
+        for recorded in &desktop.recorded {
+            match recorded {
+                Recorded::Type(text) => written.push_str(text),
+                Recorded::SetText(text) => clipboard = text.clone(),
+                Recorded::SetImage(_) => clipboard.clear(),
+                Recorded::Paste => written.push_str(&clipboard),
+                Recorded::Copy | Recorded::Backspace => {}
+            }
+        }
+        written
+    }
+
+    pub fn clipboard(&self) -> String {
+        self.lock().clipboard.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Desktop> {
+        // A test that panicked while holding it has failed already
+        self.desktop.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn record(&self, recorded: Recorded) {
+        self.lock().recorded.push(recorded);
+    }
+}
+
+impl Clipboard for Fake {
+    fn get_text(&mut self) -> Result<String, String> {
+        let desktop = self.lock();
+        if desktop.unreadable {
+            return Err("The clipboard doesn't hold text".to_string());
+        }
+        Ok(desktop.clipboard.clone())
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        self.record(Recorded::SetText(text.to_string()));
+        let mut desktop = self.lock();
+        desktop.clipboard = text.to_string();
+        desktop.unreadable = false;
+        Ok(())
+    }
+
+    fn set_image(&mut self, png: &[u8]) -> Result<(), String> {
+        self.record(Recorded::SetImage(png.to_vec()));
+        let mut desktop = self.lock();
+        desktop.clipboard.clear();
+        desktop.unreadable = true;
+        Ok(())
+    }
+}
+
+impl Keyboard for Fake {
+    fn copy(&mut self) -> Result<(), String> {
+        self.record(Recorded::Copy);
+        let mut desktop = self.lock();
+        // Like apps do, copying nothing leaves the clipboard as it was
+        if !desktop.selection.is_empty() {
+            desktop.clipboard = desktop.selection.clone();
+            desktop.unreadable = false;
+        }
+        Ok(())
+    }
+
+    fn paste(&mut self) -> Result<(), String> {
+        self.record(Recorded::Paste);
+        Ok(())
+    }
+
+    fn backspace(&mut self) -> Result<(), String> {
+        self.record(Recorded::Backspace);
+        self.lock().selection.clear();
+        Ok(())
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<(), String> {
+        self.record(Recorded::Type(text.to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! The clipboard and keyboard a run reads the selection with and writes its output through. The app
+//! uses the system's, `fake` has ones that record what they were asked to do.
+
+use arboard::ImageData;
+use enigo::{Direction, Enigo, InputError, Key, Keyboard as _};
+use image::{load_from_memory, EncodableLayout};
+use lazy_static::lazy_static;
+use std::borrow::Cow;
+use std::sync::{Mutex, MutexGuard};
+use tauri::{AppHandle, Manager};
+
+#[cfg(test)]
+pub mod fake;
+
+lazy_static! {
+    static ref DESKTOP: Mutex<()> = Mutex::new(());
+}
+
+/// A turn at the focused app and the clipboard, so runs don't copy, delete or paste over each
+/// other. Only taken while copying the selection or writing output, never while a process
+/// generates, so runs still go at the same time.
+pub fn desktop() -> MutexGuard<'static, ()> {
+    // Whatever panicked while holding it left nothing to clean up
+    DESKTOP.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub trait Clipboard {
+    fn get_text(&mut self) -> Result<String, String>;
+    fn set_text(&mut self, text: &str) -> Result<(), String>;
+    fn set_image(&mut self, png: &[u8]) -> Result<(), String>;
+}
+
+/// Sends keystrokes to the app that has focus
+pub trait Keyboard {
+    /// Copies the selection to the clipboard
+    fn copy(&mut self) -> Result<(), String>;
+    fn paste(&mut self) -> Result<(), String>;
+    fn backspace(&mut self) -> Result<(), String>;
+    fn type_text(&mut self, text: &str) -> Result<(), String>;
+}
+
+/// The system's keyboard
+pub fn keyboard() -> Result<Enigo, String> {
+    Enigo::new(&enigo::Settings::default()).map_err(|e| format!("Failed to connect to the keyboard: {}", e))
+}
+
+impl Keyboard for Enigo {
+    fn copy(&mut self) -> Result<(), String> {
+        #[cfg(target_os = "macos")]
+        {
+            self.key(Key::Meta, Direction::Release).map_err(input_error)?;
+            self.key(Key::Meta, Direction::Press).map_err(input_error)?;
+            // self.key(Key::Unicode('c'), Direction::Click)
+            self.raw(8, Direction::Click).map_err(input_error)?;
+            self.key(Key::Meta, Direction::Release).map_err(input_error)?;
+        }
+
+        #[cfg(not(target_os = "macos"))]
+        {
+            // For Windows and Linux, use Ctrl key
+            self.key(Key::LControl, Direction::Press).map_err(input_error)?;
+            self.key(Key::Unicode('c'), Direction::Click).map_err(input_error)?;
+            self.key(Key::LControl, Direction::Release).map_err(input_error)?;
+        }
+        Ok(())
+    }
+
+    fn paste(&mut self) -> Result<(), String> {
+        self.key(Key::Meta, Direction::Release).map_err(input_error)?;
+        self.key(Key::Meta, Direction::Press).map_err(input_error)?;
+        // This keeps causing a bad access in `unsafe`: enigo-0.2.0-rc2/src/macos/macos_impl.rs:631
+        // self.key(Key::Unicode('v'), Direction::Click)
+        self.raw(9, Direction::Click).map_err(input_error)?;
+        self.key(Key::Meta, Direction::Release).map_err(input_error)
+    }
+
+    fn backspace(&mut self) -> Result<(), String> {
+        self.key(Key::Backspace, Direction::Click).map_err(input_error)
+    }
+
+    fn type_text(&mut self, text: &str) -> Result<(), String> {
+        self.text(text).map_err(|e| format!("Failed to type out text: {}", e))
+    }
+}
+
+fn input_error(e: InputError) -> String {
+    format!("Failed to press keys: {}", e)
+}
+
+/// The system's clipboard, through the one Tauri keeps
+impl Clipboard for AppHandle {
+    fn get_text(&mut self) -> Result<String, String> {
+        let clipboard_manager = self.clipboard_manager();
+        let mut clipboard = clipboard_manager
+            .clipboard
+            .lock()
+            .map_err(|e| format!("Failed to get clipboard: {}", e))?;
+        clipboard.get_text().map_err(|e| format!("Failed to get clipboard: {}", e))
+    }
+
+    fn set_text(&mut self, text: &str) -> Result<(), String> {
+        let clipboard_manager = self.clipboard_manager();
+        let mut clipboard = clipboard_manager
+            .clipboard
+            .lock()
+            .map_err(|e| format!("Failed to copy text to clipboard: {}", e))?;
+        clipboard.set_text(text).map_err(|e| format!("Failed to copy text to clipboard: {}", e))
+    }
+
+    fn set_image(&mut self, png: &[u8]) -> Result<(), String> {
+        // Convert binary data to an image format (PNG)
+        let img = load_from_memory(png).map_err(|e| format!("Failed to load image from memory: {}", e))?;
+        let rgba = img.into_rgba8();
+        let image = ImageData {
+            bytes: Cow::from(rgba.as_bytes()),
+            width: rgba.width() as usize,
+            height: rgba.height() as usize,
+        };
+
+        let clipboard_manager = self.clipboard_manager();
+        let mut clipboard = clipboard_manager
+            .clipboard
+            .lock()
+            .map_err(|e| format!("Failed to copy image to clipboard: {}", e))?;
+        clipboard.set_image(image).map_err(|e| format!("Failed to copy image to clipboard: {}", e))
+    }
+}
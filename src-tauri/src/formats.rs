@@ -0,0 +1,72 @@
+//! The formats settings files can be written in, told apart by their extension
+
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Extensions of settings files, in the order they're looked for, so a `settings.toml` wins over
+/// the `settings.json` created on the first start
+pub const EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// The format of `path`, JSON unless its extension says otherwise
+    pub fn of(path: &Path) -> Format {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            _ => Format::Json,
+        }
+    }
+
+    pub fn parse(self, data: &str) -> Result<Value, String> {
+        match self {
+            Format::Json => serde_json::from_str(data).map_err(|e| e.to_string()),
+            Format::Toml => toml::from_str(data).map_err(|e| e.to_string()),
+            Format::Yaml => serde_yaml::from_str(data).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn write<T: Serialize>(self, value: &T) -> Result<String, String> {
+        if self == Format::Json {
+            return serde_json::to_string_pretty(value).map_err(|e| e.to_string());
+        }
+
+        // Neither has a null, and leaving out what isn't set reads better anyway
+        let mut value = serde_json::to_value(value).map_err(|e| e.to_string())?;
+        remove_nulls(&mut value);
+        match self {
+            Format::Toml => toml::to_string_pretty(&value).map_err(|e| e.to_string()),
+            _ => serde_yaml::to_string(&value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// The file named `stem` in `dir`, in whichever format there is one, JSON when there's none yet
+pub fn find_file(dir: &Path, stem: &str) -> PathBuf {
+    candidates(dir, stem)
+        .find(|path| path.is_file())
+        .unwrap_or(dir.join(format!("{}.json", stem)))
+}
+
+/// The paths a file named `stem` in `dir` could have
+pub fn candidates<'a>(dir: &'a Path, stem: &'a str) -> impl Iterator<Item = PathBuf> + 'a {
+    EXTENSIONS.iter().map(move |extension| dir.join(format!("{}.{}", stem, extension)))
+}
+
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(remove_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
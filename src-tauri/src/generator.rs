@@ -1,131 +1,201 @@
-use crate::settings::{ProcessType, SETTINGS};
-use ollama_rs::generation::completion::request::GenerationRequest;
-use ollama_rs::Ollama;
-use std::env;
-use std::pin::Pin;
-use std::process::Stdio;
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::process::Command;
-use tokio_stream::{Stream, StreamExt};
+use crate::backends::{BackendRequest, Event, EventStream, BACKENDS};
+use crate::context::RunContext;
+use crate::conversation::Message;
+use crate::settings::ProcessType;
+use crate::template::{self, TemplateError};
+use std::env::{self, VarError};
+use std::fmt;
+use std::future::pending;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{sleep, sleep_until, Instant};
+use tokio_stream::StreamExt;
 
+#[derive(Debug)]
+pub(crate) enum RenderError {
+    /// The `env.` variable's value isn't valid UTF-8
+    InvalidEncoding(String),
+    Template(TemplateError),
+}
+
+impl From<TemplateError> for RenderError {
+    fn from(e: TemplateError) -> Self {
+        RenderError::Template(e)
+    }
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RenderError::InvalidEncoding(name) => write!(f, "Variable ${} is not valid UTF-8", name),
+            RenderError::Template(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Renders the prompt with the variables of the run, see `template` for the syntax. plock's own
+/// environment is only available through the `env.` prefix, e.g. `${env.HOME}`.
+pub(crate) fn render_prompt(prompt: String, context: &RunContext) -> Result<String, RenderError> {
+    template::render(&prompt, |name| match name.strip_prefix("env.") {
+        Some(key) => match env::var(key) {
+            Ok(value) => Ok(Some(value)),
+            Err(VarError::NotPresent) => Ok(None),
+            Err(VarError::NotUnicode(_)) => Err(RenderError::InvalidEncoding(name.to_string())),
+        },
+        None => Ok(context.get(name).map(str::to_string)),
+    })
+}
+
+/// Runs the rendered `prompt` with `process`. When `history` is given, the prompt continues that
+/// conversation instead of being sent on its own.
+///
+/// The backend gets the constants of `context`, and the variables the process exports.
+///
+/// The run stops as soon as `cancel` is set or `timeout` (or the process' own timeout) passes,
+/// which drops the backend's stream and with it any request or child process still running.
 pub(crate) async fn generate(
-    context: String,
+    prompt: String,
     process: ProcessType,
-) -> Pin<Box<dyn Stream<Item=String>>> {
-    let final_context = {
-        let mut partial = context;
-        let settings = SETTINGS.lock().unwrap();
-        for key in settings.environment.keys() {
-            partial = partial.replace(
-                &format!("${}", key),
-                env::var_os(key).unwrap().to_str().unwrap(),
-            );
-        }
-        partial
+    history: Option<Vec<Message>>,
+    context: &RunContext,
+    cancel: Arc<AtomicBool>,
+    timeout: Option<Duration>,
+) -> EventStream {
+    let environment = context.exports(process.export());
+    let timeout = match (timeout, process.timeout()) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
     };
 
-    match process {
-        ProcessType::Ollama => {
-            let model = if let Some(s) = env::var_os("OLLAMA_MODEL") {
-                s.to_string_lossy().to_string()
-            } else {
-                "openhermes2.5-mistral".to_string()
-            };
+    let (name, options) = process.backend();
+    let backend = { BACKENDS.read().unwrap().get(&name) };
+    let Some(backend) = backend else {
+        let error = format!("No backend named \"{}\"", name);
+        return Box::pin(tokio_stream::once(Event::Error(error)));
+    };
 
-            let request = GenerationRequest::new(
-                model, final_context,
-            );
-            Box::pin(async_stream::stream! {
-                let ollama = Ollama::default();
-                let mut stream = ollama.generate_stream(request).await.unwrap();
-
-                while let Some(Ok(res)) = stream.next().await {
-                    for out in res.into_iter() {
-                        yield out.response;
-                    }
-                }
-            })
-        }
-        ProcessType::Command(custom_command) => {
-            let environment = { SETTINGS.lock().unwrap().environment.clone() };
-            let child = if custom_command.is_empty() {
-                #[cfg(target_os = "windows")] {
-                    Command::new("cmd")
-                        .envs(environment)
-                        .arg("/C")
-                        .arg(final_context)
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::piped())
-                        .spawn()
-                }
-                #[cfg(not(target_os = "windows"))]
-                {
-                    Command::new("sh")
-                        .envs(environment)
-                        .arg("-c")
-                        .arg(final_context)
-                        .stdout(Stdio::piped())
-                        .stderr(Stdio::piped())
-                        .spawn()
-                }
-            } else {
-                Command::new(&custom_command[0])
-                    .envs(environment)
-                    .args(&custom_command[1..])
-                    .arg(&final_context)
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .spawn()
-            };
+    let events = backend.generate(BackendRequest {
+        prompt,
+        history,
+        options,
+        environment,
+    }).await;
+    guard(events, cancel, timeout)
+}
 
-            let child = match child {
-                Ok(child) => child,
-                Err(e) => {
-                    return Box::pin(async_stream::stream! { yield e.to_string() });
-                }
+enum Next {
+    Event(Option<Event>),
+    Cancelled,
+    TimedOut(Duration),
+}
+
+fn guard(mut events: EventStream, cancel: Arc<AtomicBool>, timeout: Option<Duration>) -> EventStream {
+    let deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+
+    Box::pin(async_stream::stream! {
+        loop {
+            let next = tokio::select! {
+                event = events.next() => Next::Event(event),
+                _ = cancelled(&cancel) => Next::Cancelled,
+                timeout = expired(deadline) => Next::TimedOut(timeout),
             };
 
-            let stdout = BufReader::new(child.stdout.expect("Failed to take stdout of child"));
-            let stderr = BufReader::new(child.stderr.expect("Failed to take stderr of child"));
-
-            let stream = async_stream::stream! {
-                let mut reader = stdout;
-                let mut std_err_reader = stderr;
-                let mut buffer = Vec::new();
-                let mut err_buffer = Vec::new();
-
-                let mut should_break = false;
-                loop {
-                    buffer.clear();
-                    let mut temp_buf = [0; 1024]; // Temporary buffer for each read
-                    match reader.read(&mut temp_buf).await {
-                        Ok(0) => { should_break = true }, // EOF reached
-                        Ok(size) => {
-                            buffer.extend_from_slice(&temp_buf[..size]);
-                            yield String::from_utf8_lossy(&buffer).to_string();
-                        },
-                        Err(e) => {
-                            eprintln!("Error reading from stdout: {}", e);
-                            break;
-                        }
-                    }
-
-                    err_buffer.clear();
-                    let mut err_buf = [0; 1024]; // Temporary buffer for each read
-                    if let Ok(size) = std_err_reader.read(&mut err_buf).await {
-                        err_buffer.extend_from_slice(&err_buf[..size]);
-                        yield String::from_utf8_lossy(&err_buffer).to_string();
-                    } else {
-                        should_break = true;
-                    }
-
-                    if should_break {
-                        break;
-                    }
+            match next {
+                Next::Event(Some(event)) => yield event,
+                Next::Event(None) => break,
+                Next::Cancelled => {
+                    eprintln!("Run cancelled");
+                    yield Event::Cancelled;
+                    break;
                 }
-            };
+                Next::TimedOut(timeout) => {
+                    eprintln!("Run timed out after {}s", timeout.as_secs());
+                    yield Event::TimedOut(timeout);
+                    break;
+                }
+            }
+        }
+    })
+}
 
-            Box::pin(stream)
+async fn cancelled(cancel: &AtomicBool) {
+    while !cancel.load(Ordering::SeqCst) {
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+async fn expired(deadline: Option<(Instant, Duration)>) -> Duration {
+    match deadline {
+        Some((deadline, timeout)) => {
+            sleep_until(deadline).await;
+            timeout
         }
+        None => pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn never_ending() -> EventStream {
+        Box::pin(tokio_stream::pending())
+    }
+
+    #[tokio::test]
+    async fn passes_events_through_until_the_stream_ends() {
+        let events: EventStream = Box::pin(tokio_stream::iter(vec![
+            Event::Text("a".to_string()),
+            Event::Text("b".to_string()),
+        ]));
+
+        let events = guard(events, Arc::new(AtomicBool::new(false)), Some(Duration::from_secs(10)));
+
+        assert_eq!(events.collect::<Vec<Event>>().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let timeout = Duration::from_millis(50);
+
+        let events = guard(never_ending(), Arc::new(AtomicBool::new(false)), Some(timeout))
+            .collect::<Vec<Event>>()
+            .await;
+
+        assert!(matches!(events[..], [Event::TimedOut(after)] if after == timeout));
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let set = cancel.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_millis(50)).await;
+            set.store(true, Ordering::SeqCst);
+        });
+
+        let events = guard(never_ending(), cancel, None).collect::<Vec<Event>>().await;
+
+        assert!(matches!(events[..], [Event::Cancelled]));
+    }
+
+    #[tokio::test]
+    async fn uses_the_shorter_of_the_trigger_and_process_timeout() {
+        let process = serde_json::from_value(serde_json::json!({
+            "command": {"command": ["sleep", "10"], "input_mode": "stdin", "timeout_secs": 1},
+        }))
+        .unwrap();
+        let context = RunContext::new(Default::default());
+        let started = Instant::now();
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let events = generate(String::new(), process, None, &context, cancel, Some(Duration::from_secs(60)))
+            .await
+            .collect::<Vec<Event>>()
+            .await;
+
+        assert!(matches!(events[..], [Event::TimedOut(after)] if after == Duration::from_secs(1)));
+        assert!(started.elapsed() < Duration::from_secs(5));
     }
 }
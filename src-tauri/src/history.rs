@@ -0,0 +1,286 @@
+//! Every trigger that ran, kept as a line of json in `history.jsonl` next to the settings, and
+//! trimmed to what `HistorySettings` allows as runs are added.
+
+use crate::jobs::JobId;
+use crate::settings::HistorySettings;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::{command, AppHandle};
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+lazy_static! {
+    /// Held while the file is written, as runs finish at the same time
+    static ref WRITING: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    /// When the trigger started, in seconds since the epoch
+    pub started_at: u64,
+    /// The job the trigger ran as part of, `plock run` has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<JobId>,
+    /// Name of the trigger, or its position when it has none
+    pub trigger: String,
+    /// Name of the process, or its backend when it has none
+    pub process: String,
+    pub prompt: String,
+    pub selection: String,
+    pub output: String,
+    pub duration_ms: u64,
+    /// Why the trigger failed, if it did
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub cancelled: bool,
+}
+
+/// Narrows down `query`, everything is returned when nothing is set
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Query {
+    pub trigger: Option<String>,
+    /// Text the prompt, selection or output contains, ignoring case
+    pub search: Option<String>,
+    /// Only runs that failed or were cancelled
+    pub failed: bool,
+    /// The most entries returned, the newest ones
+    pub limit: Option<usize>,
+}
+
+/// The history of the app keeping its data in `dir`
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+    settings: HistorySettings,
+}
+
+impl History {
+    pub fn new(dir: &Path, settings: HistorySettings) -> Self {
+        Self { path: history_path(dir), settings }
+    }
+
+    /// Adds `entry`, dropping whatever `max_entries` and `max_age_days` no longer allow
+    pub fn record(&self, entry: Entry) -> Result<(), String> {
+        if !self.settings.enabled {
+            return Ok(());
+        }
+        let _writing = WRITING.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut entries = read_entries(&self.path)?;
+        let count = entries.len();
+        if let Some(days) = self.settings.max_age_days {
+            let oldest = now().saturating_sub(days * 24 * 60 * 60);
+            entries.retain(|entry| entry.started_at >= oldest);
+        }
+        let excess = (entries.len() + 1).saturating_sub(self.settings.max_entries);
+        entries.drain(..excess.min(entries.len()));
+        // Nothing is kept at all, including the new run
+        let keep = self.settings.max_entries > 0;
+
+        // Nothing to drop, so the rest of the file can stay as it is
+        if entries.len() == count && keep {
+            let line = to_line(&entry)?;
+            return OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .and_then(|mut file| file.write_all(line.as_bytes()))
+                .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e));
+        }
+
+        if keep {
+            entries.push(entry);
+        }
+        let data = entries.iter().map(to_line).collect::<Result<String, String>>()?;
+        // Written aside and moved over the history, so it isn't lost when writing fails halfway
+        let temp = self.path.with_extension("jsonl.tmp");
+        fs::write(&temp, data)
+            .and_then(|_| fs::rename(&temp, &self.path))
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))
+    }
+}
+
+pub fn history_path(dir: &Path) -> PathBuf {
+    dir.join(HISTORY_FILE)
+}
+
+/// The entries in the history at `path` that match `query`, newest first
+pub fn query(path: &Path, query: &Query) -> Result<Vec<Entry>, String> {
+    let search = query.search.as_ref().map(|search| search.to_lowercase());
+    let matches = |entry: &Entry| {
+        query.trigger.as_ref().is_none_or(|trigger| &entry.trigger == trigger)
+            && (!query.failed || entry.error.is_some() || entry.cancelled)
+            && search.as_ref().is_none_or(|search| {
+                [&entry.prompt, &entry.selection, &entry.output]
+                    .iter()
+                    .any(|text| text.to_lowercase().contains(search))
+            })
+    };
+
+    Ok(read_entries(path)?
+        .into_iter()
+        .rev()
+        .filter(matches)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect())
+}
+
+#[command]
+pub fn get_history(app_handle: AppHandle, query: Query) -> Result<Vec<Entry>, String> {
+    let dir = app_handle.path_resolver().app_local_data_dir().ok_or(
+        "Failed to get local data dir".to_string()
+    )?;
+    self::query(&history_path(&dir), &query)
+}
+
+/// Seconds since the epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or_default()
+}
+
+/// The entries at `path`, oldest first. Lines that can't be read, e.g. because writing them was
+/// cut short, are left out.
+fn read_entries(path: &Path) -> Result<Vec<Entry>, String> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+    };
+
+    Ok(data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                eprintln!("Skipping a run in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect())
+}
+
+fn to_line(entry: &Entry) -> Result<String, String> {
+    serde_json::to_string(entry).map(|line| line + "\n").map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(trigger: &str, started_at: u64) -> Entry {
+        Entry {
+            started_at,
+            job: None,
+            trigger: trigger.to_string(),
+            process: "mock".to_string(),
+            prompt: format!("prompt of {}", trigger),
+            selection: String::new(),
+            output: format!("output of {}", trigger),
+            duration_ms: 1,
+            error: None,
+            cancelled: false,
+        }
+    }
+
+    fn history(dir: &Path, max_entries: usize, max_age_days: Option<u64>) -> History {
+        History::new(dir, HistorySettings { enabled: true, max_entries, max_age_days })
+    }
+
+    fn triggers(path: &Path) -> Vec<String> {
+        read_entries(path).unwrap().into_iter().map(|entry| entry.trigger).collect()
+    }
+
+    #[test]
+    fn appends_while_nothing_needs_dropping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = history_path(dir.path());
+        // Rewriting the file would drop the line that can't be read
+        fs::write(&path, "not json\n").unwrap();
+
+        let history = history(dir.path(), 10, None);
+        history.record(entry("a", now())).unwrap();
+        history.record(entry("b", now())).unwrap();
+
+        assert!(fs::read_to_string(&path).unwrap().starts_with("not json\n"));
+        assert_eq!(triggers(&path), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn drops_the_oldest_beyond_max_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = history_path(dir.path());
+        fs::write(&path, "not json\n").unwrap();
+
+        let history = history(dir.path(), 2, None);
+        for trigger in ["a", "b", "c"] {
+            history.record(entry(trigger, now())).unwrap();
+        }
+
+        assert!(!fs::read_to_string(&path).unwrap().contains("not json"));
+        assert_eq!(triggers(&path), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn drops_runs_older_than_max_age_days() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = history_path(dir.path());
+        let day = 24 * 60 * 60;
+        history(dir.path(), 10, None).record(entry("old", now() - 3 * day)).unwrap();
+        history(dir.path(), 10, None).record(entry("recent", now() - day)).unwrap();
+
+        history(dir.path(), 10, Some(2)).record(entry("new", now())).unwrap();
+
+        assert_eq!(triggers(&path), vec!["recent", "new"]);
+    }
+
+    #[test]
+    fn keeps_nothing_when_max_entries_is_0() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = history_path(dir.path());
+        history(dir.path(), 10, None).record(entry("a", now())).unwrap();
+
+        history(dir.path(), 0, None).record(entry("b", now())).unwrap();
+        history(dir.path(), 0, None).record(entry("c", now())).unwrap();
+
+        assert!(triggers(&path).is_empty());
+    }
+
+    #[test]
+    fn records_nothing_when_disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = HistorySettings { enabled: false, ..HistorySettings::default() };
+        History::new(dir.path(), settings).record(entry("a", now())).unwrap();
+
+        assert!(!history_path(dir.path()).exists());
+    }
+
+    #[test]
+    fn queries_newest_first_with_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = history_path(dir.path());
+        let history = history(dir.path(), 10, None);
+        history.record(entry("fix", 1)).unwrap();
+        history.record(Entry { error: Some("failed".to_string()), ..entry("fix", 2) }).unwrap();
+        history.record(Entry { cancelled: true, ..entry("summarize", 3) }).unwrap();
+        history.record(Entry { selection: "Needle".to_string(), ..entry("summarize", 4) }).unwrap();
+
+        let started = |query: Query| -> Vec<u64> {
+            self::query(&path, &query).unwrap().iter().map(|entry| entry.started_at).collect()
+        };
+
+        assert_eq!(started(Query::default()), vec![4, 3, 2, 1]);
+        assert_eq!(started(Query { trigger: Some("fix".to_string()), ..Query::default() }), vec![2, 1]);
+        assert_eq!(started(Query { failed: true, ..Query::default() }), vec![3, 2]);
+        assert_eq!(started(Query { search: Some("needle".to_string()), ..Query::default() }), vec![4]);
+        assert_eq!(started(Query { search: Some("OUTPUT OF FIX".to_string()), ..Query::default() }), vec![2, 1]);
+        assert_eq!(started(Query { limit: Some(1), ..Query::default() }), vec![4]);
+    }
+}
@@ -0,0 +1,73 @@
+//! Settings files pulling in other files with `include`, e.g. processes and prompts a team shares
+//! in a git repository, while everyone keeps their own shortcuts in `settings.json`.
+//!
+//! Included files are merged in order, each on top of the ones before it, and the including file
+//! on top of them all, by the same rules as overlay profiles (see `profiles`). Included files can
+//! include files themselves, and are upgraded in memory when they're from an older version, but
+//! never written to.
+
+use crate::migrations::migrate;
+use crate::profiles::merge;
+use crate::settings::read_value;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Merges the files `value`, read from `path`, includes into it, adding every file read to `files`
+pub fn resolve_includes(value: Value, path: &Path, files: &mut Vec<PathBuf>) -> Result<Value, String> {
+    let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+    resolve(value, &path, &mut vec![path.clone()], files)
+}
+
+fn resolve(mut value: Value, path: &Path, chain: &mut Vec<PathBuf>, files: &mut Vec<PathBuf>) -> Result<Value, String> {
+    let Some(includes) = value.as_object_mut().and_then(|value| value.remove("include")) else {
+        return Ok(value);
+    };
+    let includes = serde_json::from_value::<Vec<PathBuf>>(includes)
+        .map_err(|e| format!("{}: `include` must be a list of paths: {}", path.display(), e))?;
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut merged = Value::Object(Map::new());
+    for include in includes {
+        let include = fs::canonicalize(dir.join(&include))
+            .map_err(|e| format!("{}: Failed to include {}: {}", path.display(), include.display(), e))?;
+        if chain.contains(&include) {
+            return Err(format!("{}: Including {} would include it again", path.display(), include.display()));
+        }
+
+        // Before reading it, so fixing it is noticed when it can't be read
+        files.push(include.clone());
+        let mut included = read_value(&include)?;
+        migrate(&mut included).map_err(|e| format!("{}: {}", include.display(), e))?;
+        relocate_paths(&mut included, include.parent().unwrap_or(Path::new(".")));
+
+        chain.push(include.clone());
+        let included = resolve(included, &include, chain, files)?;
+        chain.pop();
+        merge(&mut merged, included);
+    }
+
+    merge(&mut merged, value);
+    Ok(merged)
+}
+
+/// Makes the paths of prompt files in an included file relative to the file, rather than to the
+/// settings file including it
+fn relocate_paths(value: &mut Value, dir: &Path) {
+    let relocate = |path: &mut Value| {
+        if let Some(relative) = path.as_str() {
+            *path = json!(dir.join(relative));
+        }
+    };
+
+    if let Some(prompts) = value.get_mut("prompts").and_then(Value::as_array_mut) {
+        for prompt in prompts {
+            if let Some(file) = prompt.get_mut("file") {
+                relocate(file);
+            }
+        }
+    }
+    if let Some(dirs) = value.get_mut("prompt_dirs").and_then(Value::as_array_mut) {
+        dirs.iter_mut().for_each(relocate);
+    }
+}
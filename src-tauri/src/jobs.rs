@@ -0,0 +1,129 @@
+//! Runs of triggers started from shortcuts. Every run is a job with an id and a cancellation of its
+//! own, and what happens when a trigger is invoked while it's still running is up to its `overlap`.
+
+use crate::settings::Overlap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub type JobId = u64;
+
+pub struct Job {
+    pub id: JobId,
+    /// Stops the run when set
+    pub cancel: Arc<AtomicBool>,
+    /// Held while the job runs, so runs of the same trigger take turns
+    pub lane: Arc<tokio::sync::Mutex<()>>,
+}
+
+struct Running {
+    id: JobId,
+    trigger: usize,
+    cancel: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    last_id: AtomicU64,
+    /// Triggers whose shortcut was pressed, they're started once the keys are released
+    requested: Mutex<Vec<usize>>,
+    /// Jobs that are running or waiting for their turn
+    running: Mutex<Vec<Running>>,
+    lanes: Mutex<HashMap<usize, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Jobs {
+    pub fn request(&self, trigger: usize) {
+        self.requested.lock().unwrap().push(trigger);
+    }
+
+    /// The triggers requested since the last call, in the order they were
+    pub fn take_requested(&self) -> Vec<usize> {
+        std::mem::take(&mut *self.requested.lock().unwrap())
+    }
+
+    /// A job for the trigger at `index`, unless `overlap` rejects it because the trigger is running
+    pub fn start(&self, trigger: usize, overlap: Overlap) -> Option<Job> {
+        let mut running = self.running.lock().unwrap();
+        let mut previous = running.iter().filter(|job| job.trigger == trigger).peekable();
+        match overlap {
+            Overlap::Queue => {}
+            Overlap::Reject if previous.peek().is_some() => return None,
+            Overlap::Reject => {}
+            Overlap::CancelPrevious => previous.for_each(|job| job.cancel.store(true, Ordering::SeqCst)),
+        }
+
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let cancel = Arc::new(AtomicBool::new(false));
+        running.push(Running { id, trigger, cancel: cancel.clone() });
+        let lane = self.lanes.lock().unwrap().entry(trigger).or_default().clone();
+        Some(Job { id, cancel, lane })
+    }
+
+    pub fn finish(&self, id: JobId) {
+        self.running.lock().unwrap().retain(|job| job.id != id);
+    }
+
+    /// Cancels every job, including those waiting for their turn
+    pub fn cancel_all(&self) {
+        for job in self.running.lock().unwrap().iter() {
+            job.cancel.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_runs_of_the_same_trigger() {
+        let jobs = Jobs::default();
+        let first = jobs.start(0, Overlap::Queue).unwrap();
+        let second = jobs.start(0, Overlap::Queue).unwrap();
+
+        assert_ne!(first.id, second.id);
+        assert!(Arc::ptr_eq(&first.lane, &second.lane));
+        assert!(!first.cancel.load(Ordering::SeqCst));
+        assert!(!second.cancel.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn rejects_a_trigger_until_its_run_finishes() {
+        let jobs = Jobs::default();
+        let first = jobs.start(0, Overlap::Reject).unwrap();
+
+        assert!(jobs.start(0, Overlap::Reject).is_none());
+        assert!(jobs.start(1, Overlap::Reject).is_some());
+        assert!(!first.cancel.load(Ordering::SeqCst));
+
+        jobs.finish(first.id);
+        assert!(jobs.start(0, Overlap::Reject).is_some());
+    }
+
+    #[test]
+    fn cancels_the_previous_runs_of_the_trigger() {
+        let jobs = Jobs::default();
+        let first = jobs.start(0, Overlap::Queue).unwrap();
+        let other = jobs.start(1, Overlap::Queue).unwrap();
+
+        let second = jobs.start(0, Overlap::CancelPrevious).unwrap();
+
+        assert!(first.cancel.load(Ordering::SeqCst));
+        assert!(!other.cancel.load(Ordering::SeqCst));
+        assert!(!second.cancel.load(Ordering::SeqCst));
+        assert!(!Arc::ptr_eq(&first.lane, &other.lane));
+    }
+
+    #[test]
+    fn cancels_every_job() {
+        let jobs = Jobs::default();
+        let first = jobs.start(0, Overlap::Queue).unwrap();
+        let second = jobs.start(1, Overlap::Queue).unwrap();
+
+        jobs.cancel_all();
+
+        assert!(first.cancel.load(Ordering::SeqCst));
+        assert!(second.cancel.load(Ordering::SeqCst));
+    }
+}
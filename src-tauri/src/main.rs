@@ -3,61 +3,101 @@
 
 extern crate core;
 
-use crate::generator::generate;
-use crate::settings::{SelectionAction, Step, SETTINGS};
-use arboard::ImageData;
-use base64::decode;
-use enigo::{Direction, Enigo, InputResult, Key, Keyboard};
-use image::{load_from_memory, EncodableLayout};
+use crate::context::RunContext;
+use crate::history::History;
+use crate::jobs::{Job, Jobs};
+use crate::pipeline::{Pipeline, Screen};
+use crate::settings::{SelectionAction, Settings, Trigger, SETTINGS};
 use rdev::{listen, EventType, Key as RdevKey};
-use std::borrow::Cow;
 use std::collections::HashSet;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::Ordering;
 use std::sync::Mutex;
-use std::{env, sync::Arc, thread};
+use std::{sync::Arc, thread};
+use tauri::api::notification::Notification;
 use tauri::{
-    AppHandle, CustomMenuItem, GlobalShortcutManager, Manager, SystemTray, SystemTrayEvent,
-    SystemTrayMenu, SystemTrayMenuItem, WindowEvent,
+    AppHandle, CustomMenuItem, Manager, SystemTray, SystemTrayEvent,
+    SystemTrayMenu, SystemTrayMenuItem, SystemTraySubmenu, WindowEvent,
 };
 use tokio::runtime::Runtime;
-use tokio_stream::StreamExt;
 
 #[cfg(feature = "ocr")]
 mod ocr;
 
+mod backends;
+mod cli;
+mod context;
+mod conversation;
+mod devices;
+mod formats;
 mod generator;
+mod history;
+mod includes;
+mod jobs;
+mod migrations;
+mod pipeline;
+mod profiles;
+mod prompts;
 mod settings;
+mod template;
+mod validation;
 
 fn make_tray() -> SystemTray {
+    SystemTray::new().with_menu(make_tray_menu(&[], None))
+}
+
+fn make_tray_menu(profiles: &[String], active_profile: Option<&str>) -> SystemTrayMenu {
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let load_settings = CustomMenuItem::new("load_settings".to_string(), "Load Settings");
     let settings_location =
         CustomMenuItem::new("settings_location".to_string(), "<Settings Location>").disabled();
-    let tray_menu = SystemTrayMenu::new()
+
+    // Item ids are the profile name after `profile:`, nothing for settings.json
+    let profile_item = |name: Option<&str>| {
+        let item = CustomMenuItem::new(
+            format!("profile:{}", name.unwrap_or_default()),
+            name.unwrap_or("Default"),
+        );
+        if name == active_profile { item.selected() } else { item }
+    };
+    let profiles_menu = profiles.iter().fold(
+        SystemTrayMenu::new().add_item(profile_item(None)),
+        |menu, profile| menu.add_item(profile_item(Some(profile))),
+    );
+
+    SystemTrayMenu::new()
         .add_item(load_settings)
+        .add_submenu(SystemTraySubmenu::new("Profiles", profiles_menu))
         .add_item(settings_location)
         .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(quit);
-    SystemTray::new().with_menu(tray_menu)
+        .add_item(quit)
 }
 
-fn main() {
-    let exit_flag = Arc::new(AtomicBool::new(false));
-    let trigger_flag = Arc::new(AtomicBool::new(false));
-    let trigger_index = Arc::new(AtomicUsize::new(0));
-
-    let old_clipboard = Arc::new(Mutex::new(String::new()));
-
-    let trigger_flag_clone = trigger_flag.clone();
-    let trigger_index_clone = trigger_index.clone();
+/// Rebuilds the tray menu, so it lists the profiles there are now
+fn refresh_tray(app_handle: &AppHandle) {
+    let (profiles, active) = settings::get_profiles(app_handle.clone()).unwrap_or_default();
+    let tray = app_handle.tray_handle();
+    if let Err(e) = tray.set_menu(make_tray_menu(&profiles, active.as_deref())) {
+        eprintln!("Failed to update the tray menu: {}", e);
+        return;
+    }
+    if let Ok(path) = settings::ensure_local_data_dir(app_handle.clone()) {
+        let _ = tray.get_item("settings_location").set_title(path);
+    }
+}
 
-    let trigger_flag_system_tray_clone = trigger_flag.clone();
-    let trigger_index_system_tray_clone = trigger_index.clone();
+fn main() {
+    let context = tauri::generate_context!();
 
-    let trigger_flag_listen_clone = trigger_flag.clone();
-    let trigger_index_listen_clone = trigger_index.clone();
+    let args = std::env::args().collect::<Vec<String>>();
+    match args.get(1).map(String::as_str) {
+        Some("run") => std::process::exit(cli::run(&args[2..], context.config())),
+        Some("history") => std::process::exit(cli::history(&args[2..], context.config())),
+        _ => {}
+    }
 
-    let exit_flag_listen_clone = exit_flag.clone();
+    let jobs = Arc::new(Jobs::default());
+    let jobs_system_tray_clone = jobs.clone();
+    let jobs_listen_clone = jobs.clone();
 
     let rt = Arc::new(Runtime::new().unwrap());
     let rt_clone = Arc::clone(&rt);
@@ -89,44 +129,25 @@ fn main() {
             }
 
             if escape_pressed {
-                exit_flag_listen_clone.store(true, Ordering::SeqCst);
+                jobs_listen_clone.cancel_all();
                 return;
             }
 
             if !pressed_keys.is_empty() {
                 return;
             }
+            // `trigger_action` locks them as well
+            drop(pressed_keys);
 
-            if trigger_flag_listen_clone.load(Ordering::SeqCst) {
+            // If no keys are pressed, trigger the actions
+            for index in jobs_listen_clone.take_requested() {
                 println!("tried to trigger");
-                // If no keys are pressed, trigger the action
-                trigger_flag_listen_clone.store(false, Ordering::SeqCst);
-                // Reset exit flag
-                exit_flag_listen_clone.store(false, Ordering::SeqCst);
-
-                let original_clipboard_contents = {
-                    let mut handle = app_handle_clone.lock().unwrap();
-                    handle
-                        .as_mut()
-                        .unwrap()
-                        .clipboard_manager()
-                        .clipboard
-                        .lock()
-                        .unwrap()
-                        .get_text()
-                        .unwrap_or("".to_string())
-                };
-                let cloned_contents = original_clipboard_contents.clone();
-                {
-                    *old_clipboard.lock().unwrap() = cloned_contents;
-                }
                 trigger_action(
                     app_handle_clone.clone(),
-                    original_clipboard_contents,
                     rt_clone.clone(),
-                    exit_flag_listen_clone.clone(),
+                    jobs_listen_clone.clone(),
                     pressed_keys_clone.clone(),
-                    trigger_index_listen_clone.clone(),
+                    index,
                 );
             }
         })
@@ -135,21 +156,19 @@ fn main() {
 
     tauri::Builder::default()
         .setup(move |app| {
-            let path = settings::ensure_local_data_dir(app.app_handle())
+            settings::ensure_local_data_dir(app.app_handle())
                 .expect("Failed to create local data dir");
-            app.tray_handle()
-                .get_item("settings_location")
-                .set_title(path)
-                .unwrap();
 
-            let trigger_index_clone = trigger_index_clone.clone();
-            let trigger_flag_second_clone = trigger_flag_clone.clone();
+            let watch_handle = app.app_handle();
+            let watch_jobs = jobs.clone();
+            if let Err(e) = settings::watch_settings(move || {
+                println!("Settings changed, reloading...");
+                reload_settings(watch_handle.clone(), watch_jobs.clone());
+            }) {
+                eprintln!("{}", e);
+            }
 
-            let _ = settings::load_settings(
-                app.app_handle(),
-                trigger_index_clone.clone(),
-                trigger_flag_second_clone.clone(),
-            );
+            reload_settings(app.app_handle(), jobs.clone());
 
             #[cfg(target_os = "macos")]
             {
@@ -169,311 +188,108 @@ fn main() {
                     match id.as_str() {
                         "quit" => std::process::exit(0),
                         "load_settings" => {
-                            let trigger_index_clone = trigger_index_system_tray_clone.clone();
-                            let trigger_flag_second_clone = trigger_flag_system_tray_clone.clone();
-
-                            settings::load_settings(
-                                app.app_handle().clone(),
-                                trigger_index_clone.clone(),
-                                trigger_flag_second_clone.clone(),
-                            )
-                            .expect("Failed to load settings.")
+                            reload_settings(app.app_handle().clone(), jobs_system_tray_clone.clone())
+                        }
+                        id if id.starts_with("profile:") => {
+                            let profile = id.trim_start_matches("profile:");
+                            let profile = Some(profile).filter(|profile| !profile.is_empty());
+                            if let Err(e) = settings::switch_profile(app.app_handle(), profile) {
+                                notify_error(app, &e);
+                            }
+                            reload_settings(app.app_handle(), jobs_system_tray_clone.clone())
                         }
                         _ => {}
                     }
                 }
             }
         })
+        .invoke_handler(tauri::generate_handler![history::get_history])
         .on_window_event(|event| {
             if let WindowEvent::CloseRequested { api, .. } = event.event() {
                 event.window().hide().unwrap();
                 api.prevent_close();
             }
         })
-        .run(tauri::generate_context!())
+        .run(context)
         .expect("error while running tauri application");
 }
 
-fn handle_selection(selection_action: SelectionAction) {
-    let mut enigo = Enigo::new(&enigo::Settings::default()).unwrap();
-    #[cfg(target_os = "macos")]
-    {
-        enigo.key(Key::Meta, Direction::Release).unwrap();
-        // copy
-        enigo.key(Key::Meta, Direction::Press).unwrap();
-        // enigo.key(Key::Unicode('c'), Direction::Click).unwrap();
-        enigo.raw(8, Direction::Click).unwrap();
-        enigo.key(Key::Meta, Direction::Release).unwrap();
-    }
-
-    #[cfg(not(target_os = "macos"))]
-    {
-        // For Windows and Linux, use Ctrl key
-        enigo.key(Key::LControl, Direction::Press).unwrap();
-        enigo.key(Key::Unicode('c'), Direction::Click).unwrap();
-        enigo.key(Key::LControl, Direction::Release).unwrap();
-    }
-
-    match selection_action {
-        SelectionAction::Remove => {
-            enigo.key(Key::Backspace, Direction::Click).unwrap();
-        }
-        SelectionAction::Newline => {
-            // Why are we deleting and rewriting? something strange with enigo or something is getting locked up
-            enigo.key(Key::Backspace, Direction::Click).unwrap();
-            // enigo.key(Key::RightArrow, Direction::Click).unwrap();
-            // enigo.text("\n\n").unwrap();
+/// Loads the settings file, and tells the user what's wrong with it, if anything
+fn reload_settings(app_handle: AppHandle, jobs: Arc<Jobs>) {
+    refresh_tray(&app_handle);
+    match settings::load_settings(app_handle.clone(), jobs) {
+        Ok(warnings) => {
+            if !warnings.is_empty() {
+                let warnings = warnings.iter().map(|warning| warning.to_string()).collect::<Vec<String>>();
+                notify_error(&app_handle, &format!("Loaded settings with warnings:\n{}", warnings.join("\n")));
+            }
         }
-        SelectionAction::Nothing => {}
+        Err(e) => notify_error(&app_handle, &format!("Kept the previous settings:\n{}", e)),
     }
 }
 
-fn get_context(app_handle: Arc<Mutex<Option<AppHandle>>>, pipeline_index: Arc<AtomicUsize>) {
-    println!("preparing to copy text...");
-    let selection_action = {
-        let settings = SETTINGS.lock().unwrap();
-        let i = pipeline_index.load(Ordering::SeqCst);
-        let trigger = settings.triggers[i].clone();
-        trigger.selection_action.unwrap_or(SelectionAction::Remove)
-    };
-    handle_selection(selection_action);
-
-    let user_prompt = {
-        let mut handle = app_handle.lock().unwrap();
-        handle
-            .as_mut()
-            .unwrap()
-            .clipboard_manager()
-            .clipboard
-            .lock()
-            .unwrap()
-            .get_text()
-            .expect("Failed to get clipboard.")
-    };
-
-    {
-        SETTINGS
-            .lock()
-            .unwrap()
-            .add_env_var("SELECTION".to_string(), user_prompt.clone());
-    }
-
-    println!("copied... {}", user_prompt);
-
+/// Fills in what the trigger reads from the screen, returning whether the selection was removed
+fn get_context(
+    app_handle: &mut AppHandle,
+    selection_action: &SelectionAction,
+    context: &mut RunContext,
+) -> Result<bool, String> {
+    // Before the selection is removed, so failing leaves it as it was
     #[cfg(feature = "ocr")]
     {
         use crate::ocr::get_text_on_screen;
-        let text_on_screen = match get_text_on_screen() {
-            Ok(contents) => contents,
-            Err(e) => {
-                panic!("Failed to get text on screen: {}", e);
-            }
-        };
-        {
-            SETTINGS
-                .lock()
-                .unwrap()
-                .add_env_var("OCR".to_string(), text_on_screen.clone());
-        }
+        let text_on_screen = get_text_on_screen().map_err(|e| format!("Failed to get text on screen: {}", e))?;
+        context.set_builtin("OCR", text_on_screen);
     }
+
+    println!("preparing to copy text...");
+    let mut enigo = devices::keyboard()?;
+    pipeline::capture(&mut enigo, app_handle, selection_action, context)
 }
 
+/// Runs the trigger at `index` as a job of its own
 fn trigger_action(
     app_handle: Arc<Mutex<Option<AppHandle>>>,
-    original_clipboard_contents: String,
     rt: Arc<Runtime>,
-    exit_flag: Arc<AtomicBool>,
+    jobs: Arc<Jobs>,
     pressed_keys: Arc<Mutex<HashSet<RdevKey>>>,
-    pipeline_index: Arc<AtomicUsize>,
+    index: usize,
 ) {
-    let exit_flag_thread = exit_flag.clone();
-    {
-        SETTINGS
-            .lock()
-            .unwrap()
-            .add_env_var("CLIPBOARD".to_string(), original_clipboard_contents.clone());
-    }
-
-    get_context(app_handle.clone(), pipeline_index.clone());
-    println!("CLIPBOARD: {:?}", env::var_os("CLIPBOARD"));
-    println!("SELECTION: {:?}", env::var_os("SELECTION"));
+    let Some(mut app_handle) = app_handle.lock().unwrap().clone() else {
+        return;
+    };
+    // Reloading the settings doesn't change a run that already started
+    let settings = SETTINGS.lock().unwrap().clone();
+    // The settings were reloaded without it since the shortcut was pressed
+    let Some(trigger) = settings.triggers.get(index).cloned() else {
+        eprintln!("There is no trigger #{} anymore", index);
+        pressed_keys.lock().unwrap().clear();
+        return;
+    };
 
-    let app_handle_clone = app_handle.clone();
+    // Decided before anything is copied, so a rejected run leaves the selection alone
+    let Some(job) = jobs.start(index, trigger.overlap.unwrap_or_default()) else {
+        notify_error(&app_handle, &format!("Trigger {} is still running", trigger.label(index)));
+        pressed_keys.lock().unwrap().clear();
+        return;
+    };
+    println!("Started job {} for trigger {}", job.id, index);
 
     rt.spawn_blocking(move || {
         tokio::runtime::Handle::current().block_on(async {
-            let index = pipeline_index.clone();
-            let mut enigo = Enigo::new(&enigo::Settings::default()).unwrap();
-
-            loop {
-                // Create a pipe here so that we can shove output to other processes
-                let i = index.load(Ordering::SeqCst);
-
-                let (trigger, process_type, prompt) = {
-                    let settings = SETTINGS.lock().unwrap();
-                    let trigger = settings.triggers[i].clone();
-                    (
-                        trigger.clone(),
-                        settings.processes[trigger.process].clone(),
-                        settings.prompts[trigger.prompt].clone(),
-                    )
-                };
-
-                let mut response_stream = generate(prompt.prompt, process_type).await;
-
-                let mut whole_buffer = Vec::new();
-                let mut delta_buffer = Vec::new();
-
-                let mut did_exit = false;
-
-                {
-                    'outer: while let Some(response) = response_stream.next().await {
-                        whole_buffer.push(response.clone());
-                        delta_buffer.push(response.clone());
-
-                        let delta_output = {
-                            if delta_buffer.len() > 4 {
-                                let s = delta_buffer.clone().join("");
-                                delta_buffer.clear();
-                                s
-                            } else {
-                                "".to_string()
-                            }
-                        };
-
-                        for step in trigger.next_steps.clone() {
-                            if delta_output.is_empty() {
-                                continue;
-                            }
-                            if let Step::StreamTextToScreen = step {
-                                for chunk in delta_output.chars().collect::<Vec<char>>().chunks(19) {
-                                    enigo.text(&String::from_iter(chunk)).expect("Failed to type out text");
-                                    // Exit loop if child process has finished or exit flag is set
-                                    if exit_flag_thread.load(Ordering::SeqCst) {
-                                        did_exit = true;
-                                        break 'outer;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-
-                let mut should_continue = false;
-                if !did_exit {
-                    let delta_output = delta_buffer.join("");
-                    let whole_output = whole_buffer.join("");
-                    println!("Whole output: {}", whole_output);
-
-                    'outer: for step in trigger.next_steps {
-                        match step {
-                            Step::StreamTextToScreen => {
-                                if !delta_buffer.is_empty() {
-                                    for chunk in delta_output.chars().collect::<Vec<char>>().chunks(19) {
-                                        enigo.text(&String::from_iter(chunk)).expect("Failed to type out text");
-                                        // Exit loop if child process has finished or exit flag is set
-                                        if exit_flag_thread.load(Ordering::SeqCst) {
-                                            should_continue = false;
-                                            break 'outer;
-                                        }
-                                    }
-                                }
-                            }
-                            Step::StoreAsEnvVar(key) => {
-                                SETTINGS
-                                    .lock()
-                                    .unwrap()
-                                    .add_env_var(key, whole_output.clone());
-                            }
-                            Step::Trigger(i) => {
-                                index.store(i, Ordering::SeqCst);
-                                should_continue = true;
-                            }
-                            Step::WriteFinalTextToScreen => {
-                                let mut final_buffer = whole_buffer.clone();
-                                // Why are we deleting and rewriting? something strange with enigo or something is getting locked up
-                                if let SelectionAction::Newline = trigger
-                                    .selection_action
-                                    .clone()
-                                    .unwrap_or(SelectionAction::Remove)
-                                {
-                                    let to_insert = format!(
-                                        "{}\n\n",
-                                        env::var_os("SELECTION").unwrap().to_string_lossy()
-                                    );
-                                    final_buffer.insert(0, to_insert.clone());
-                                }
-                                let final_buffer_output = final_buffer.join("");
-
-                                {
-                                    let mut handle = app_handle_clone.lock().unwrap();
-                                    handle
-                                        .as_mut()
-                                        .unwrap()
-                                        .clipboard_manager()
-                                        .clipboard
-                                        .lock()
-                                        .unwrap()
-                                        .set_text(&final_buffer_output)
-                                        .expect("Failed to copy image to clipboard");
-                                };
-
-                                paste(&mut enigo);
-                            }
-                            Step::WriteImageToScreen => {
-                                let image_data = decode(whole_output.trim())
-                                    .expect("Failed to decode base64 string");
-
-                                // Convert binary data to an image format (PNG)
-                                let img = load_from_memory(&image_data)
-                                    .expect("Failed to load image from memory");
-                                // let mut cursor = Cursor::new(Vec::new());
-                                let rgba = img.into_rgba8();
-                                let image = ImageData {
-                                    bytes: Cow::from(rgba.as_bytes()),
-                                    width: rgba.width() as usize,
-                                    height: rgba.height() as usize,
-                                };
-
-                                // Copy the image to the clipboard
-                                {
-                                    let mut handle = app_handle_clone.lock().unwrap();
-                                    handle
-                                        .as_mut()
-                                        .unwrap()
-                                        .clipboard_manager()
-                                        .clipboard
-                                        .lock()
-                                        .unwrap()
-                                        .set_image(image)
-                                        .expect("Failed to copy image to clipboard");
-                                };
-                                paste(&mut enigo);
-                            }
-                        }
-                    }
-                }
-
-                if !should_continue {
-                    break;
-                }
-            }
-
-            exit_flag_thread.store(false, Ordering::SeqCst);
-
-            {
-                let mut handle = app_handle.lock().unwrap();
-                if let Some(old_clipboard) = env::var_os("CLIPBOARD") {
-                    handle
-                        .as_mut()
-                        .unwrap()
-                        .clipboard_manager()
-                        .clipboard
-                        .lock()
-                        .unwrap()
-                        .set_text(old_clipboard.to_string_lossy().to_string())
-                        .expect("Failed to restore clipboard.");
-                }
+            let turn = job.lane.lock().await;
+            // Cancelled while waiting for its turn
+            let result = if job.cancel.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                run_job(&mut app_handle, &settings, &trigger, index, &job).await
+            };
+            drop(turn);
+            jobs.finish(job.id);
+            println!("Finished job {}", job.id);
+
+            if let Err(e) = result {
+                notify_error(&app_handle, &e);
             }
 
             pressed_keys.lock().unwrap().clear();
@@ -481,19 +297,33 @@ fn trigger_action(
     });
 }
 
-fn paste(enigo: &mut Enigo) {
-    enigo
-        .key(Key::Meta, Direction::Release)
-        .expect("Failed to paste text");
-    enigo
-        .key(Key::Meta, Direction::Press)
-        .expect("Failed to paste text");
-    // This keeps causing a bad access in `unsafe`: enigo-0.2.0-rc2/src/macos/macos_impl.rs:631
-    // enigo.key(Key::Unicode('v'), Direction::Click).expect("Failed to paste text");
-    enigo
-        .raw(9, Direction::Click)
-        .expect("Failed to paste text");
-    enigo
-        .key(Key::Meta, Direction::Release)
-        .expect("Failed to paste text");
+/// Copies the selection and runs the trigger. Copying and writing output take turns at the
+/// focused app with other runs, see `devices::desktop`.
+async fn run_job(
+    app_handle: &mut AppHandle,
+    settings: &Settings,
+    trigger: &Trigger,
+    index: usize,
+    job: &Job,
+) -> Result<(), String> {
+    let mut context = RunContext::new(settings.environment.clone());
+    let selection_action = trigger.selection_action.clone().unwrap_or(SelectionAction::Remove);
+    let removed_selection = get_context(app_handle, &selection_action, &mut context)?;
+    println!("CLIPBOARD: {:?}", context.get("CLIPBOARD"));
+    println!("SELECTION: {:?}", context.get("SELECTION"));
+
+    let mut screen = Screen::new(devices::keyboard()?, app_handle.clone()).restoring(context.get("CLIPBOARD"));
+    let mut pipeline = Pipeline::new(settings, &mut screen, job.cancel.clone());
+    if let Some(dir) = app_handle.path_resolver().app_local_data_dir() {
+        pipeline = pipeline.with_history(History::new(&dir, settings.history.clone()), Some(job.id));
+    }
+    pipeline.run(index, &mut context, removed_selection).await
+}
+
+fn notify_error(app_handle: &AppHandle, message: &str) {
+    eprintln!("Error: {}", message);
+    let identifier = app_handle.config().tauri.bundle.identifier.clone();
+    if let Err(e) = Notification::new(identifier).title("plock").body(message).show() {
+        eprintln!("Failed to show notification: {}", e);
+    }
 }
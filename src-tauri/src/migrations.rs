@@ -0,0 +1,280 @@
+//! Upgrades settings files written by older versions of plock, before they're deserialized.
+//!
+//! Every change to the settings that older files wouldn't deserialize into (or would silently
+//! mean something else) bumps `CURRENT_VERSION` and adds a migration from the version before.
+
+use serde_json::{json, Map, Value};
+use std::path::Path;
+
+pub const CURRENT_VERSION: u64 = 2;
+
+/// `MIGRATIONS[n]` upgrades settings of version `n` to `n + 1`
+const MIGRATIONS: [fn(&mut Map<String, Value>); CURRENT_VERSION as usize] = [
+    structured_processes,
+    name_references,
+];
+
+/// Upgrades `settings` to `CURRENT_VERSION`, returning the version it had when it was older.
+/// Files without a `version` are from before versioning, which is version 0.
+pub fn migrate(settings: &mut Value) -> Result<Option<u64>, String> {
+    let settings = settings.as_object_mut().ok_or("Settings must hold an object")?;
+    let version = match settings.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or("`version` must be a number")?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "Settings are version {}, but this plock only knows up to version {}",
+            version, CURRENT_VERSION,
+        ));
+    }
+    if version == CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(settings);
+    }
+    settings.insert("version".to_string(), json!(CURRENT_VERSION));
+    Ok(Some(version))
+}
+
+/// Version 1: processes are always objects, `"ollama"` becomes `{"ollama": {}}` and
+/// `{"command": [...]}` becomes `{"command": {"command": [...]}}`
+fn structured_processes(settings: &mut Map<String, Value>) {
+    for process in list(settings, "processes") {
+        if process.as_str() == Some("ollama") {
+            *process = json!({ "ollama": {} });
+        } else if let Some(command) = process.get_mut("command").filter(|command| command.is_array()) {
+            *command = json!({ "command": command.take() });
+        }
+    }
+}
+
+/// Version 2: references by index become references by name, which keep working when the lists
+/// are reordered. Processes, and triggers other triggers continue with, get a name when they have
+/// none. References that don't point at something with a unique name are left as they are.
+fn name_references(settings: &mut Map<String, Value>) {
+    let mut process_names = names(settings, "processes");
+    for (i, process) in list(settings, "processes").iter_mut().enumerate() {
+        if process_names[i].is_none() {
+            let name = unique_name(&process_names, &suggest_process_name(process));
+            process["name"] = json!(name);
+            process_names[i] = Some(name);
+        }
+    }
+
+    let mut trigger_names = names(settings, "triggers");
+    let continued = list(settings, "triggers")
+        .iter()
+        .flat_map(|trigger| trigger["next_steps"].as_array().cloned().unwrap_or_default())
+        .filter_map(|step| step["trigger"].as_u64())
+        .collect::<Vec<u64>>();
+    for (i, trigger) in list(settings, "triggers").iter_mut().enumerate() {
+        if trigger_names[i].is_none() && continued.contains(&(i as u64)) {
+            let name = unique_name(&trigger_names, &format!("trigger {}", i));
+            trigger["name"] = json!(name);
+            trigger_names[i] = Some(name);
+        }
+    }
+
+    let prompt_names = names(settings, "prompts");
+
+    for prompt in list(settings, "prompts") {
+        rename_reference(prompt.get_mut("process"), &process_names);
+    }
+    for trigger in list(settings, "triggers") {
+        rename_reference(trigger.get_mut("process"), &process_names);
+        rename_reference(trigger.get_mut("prompt"), &prompt_names);
+        if let Some(steps) = trigger.get_mut("next_steps").and_then(Value::as_array_mut) {
+            for step in steps {
+                rename_reference(step.get_mut("trigger"), &trigger_names);
+            }
+        }
+    }
+}
+
+fn list<'a>(settings: &'a mut Map<String, Value>, key: &str) -> Vec<&'a mut Value> {
+    match settings.get_mut(key).and_then(Value::as_array_mut) {
+        Some(items) => items.iter_mut().collect(),
+        None => Vec::new(),
+    }
+}
+
+fn names(settings: &mut Map<String, Value>, key: &str) -> Vec<Option<String>> {
+    list(settings, key)
+        .into_iter()
+        .map(|item| item["name"].as_str().filter(|name| !name.is_empty()).map(String::from))
+        .collect()
+}
+
+fn rename_reference(reference: Option<&mut Value>, names: &[Option<String>]) {
+    let Some(reference) = reference else {
+        return;
+    };
+    let Some(index) = reference.as_u64().map(|index| index as usize) else {
+        return;
+    };
+    let Some(Some(name)) = names.get(index) else {
+        return;
+    };
+    if names.iter().filter(|other| other.as_ref() == Some(name)).count() == 1 {
+        *reference = json!(name);
+    }
+}
+
+fn suggest_process_name(process: &Value) -> String {
+    let Some((kind, config)) = process.as_object().and_then(|process| {
+        process.iter().find(|(key, _)| key.as_str() != "name")
+    }) else {
+        return "process".to_string();
+    };
+
+    let suggestion = match kind.as_str() {
+        // Named after the script it runs, e.g. "gpt" for `["bash", "/path/to/gpt.sh"]`
+        "command" => config["command"].as_array().and_then(|command| command.last()).and_then(|last| {
+            let stem = Path::new(last.as_str()?).file_stem()?;
+            Some(stem.to_string_lossy().to_string())
+        }),
+        "ollama" | "openai" => config["model"].as_str().map(String::from),
+        "backend" => config["name"].as_str().map(String::from),
+        _ => None,
+    };
+    suggestion.unwrap_or(kind.clone())
+}
+
+fn unique_name(taken: &[Option<String>], name: &str) -> String {
+    let is_taken = |name: &str| taken.iter().any(|taken| taken.as_deref() == Some(name));
+    if !is_taken(name) {
+        return name.to_string();
+    }
+    (2..).map(|n| format!("{} {}", name, n)).find(|name| !is_taken(name)).expect("Names run out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings as the README had them before versioning
+    fn version_0() -> Value {
+        json!({
+            "environment": {"OLLAMA_MODEL": "openhermes2.5-mistral"},
+            "processes": [
+                {"name": "Use GPT", "command": ["bash", "/scripts/gpt.sh"]},
+                {"name": "Execute text directly as script", "command": []},
+                "ollama",
+            ],
+            "prompts": [
+                {"name": "default basic", "prompt": "$SELECTION"},
+                {"name": "step", "prompt": "$STEP"},
+            ],
+            "triggers": [
+                {
+                    "trigger_with_shortcut": "Command+Shift+,",
+                    "process": 0,
+                    "prompt": 0,
+                    "next_steps": [{"store_as_env_var": "STEP"}, {"trigger": 1}],
+                },
+                {"process": 2, "prompt": 1, "next_steps": ["stream_text_to_screen"]},
+            ],
+        })
+    }
+
+    #[test]
+    fn upgrades_version_0_to_the_current_version() {
+        let mut settings = version_0();
+
+        assert_eq!(migrate(&mut settings).unwrap(), Some(0));
+
+        assert_eq!(settings, json!({
+            "environment": {"OLLAMA_MODEL": "openhermes2.5-mistral"},
+            "processes": [
+                {"name": "Use GPT", "command": {"command": ["bash", "/scripts/gpt.sh"]}},
+                {"name": "Execute text directly as script", "command": {"command": []}},
+                {"ollama": {}, "name": "ollama"},
+            ],
+            "prompts": [
+                {"name": "default basic", "prompt": "$SELECTION"},
+                {"name": "step", "prompt": "$STEP"},
+            ],
+            "triggers": [
+                {
+                    "trigger_with_shortcut": "Command+Shift+,",
+                    "process": "Use GPT",
+                    "prompt": "default basic",
+                    "next_steps": [{"store_as_env_var": "STEP"}, {"trigger": "trigger 1"}],
+                },
+                {
+                    "process": "ollama",
+                    "prompt": "step",
+                    "next_steps": ["stream_text_to_screen"],
+                    "name": "trigger 1",
+                },
+            ],
+            "version": CURRENT_VERSION,
+        }));
+    }
+
+    #[test]
+    fn leaves_upgraded_settings_alone() {
+        let mut settings = version_0();
+        migrate(&mut settings).unwrap();
+        let upgraded = settings.clone();
+
+        assert_eq!(migrate(&mut settings).unwrap(), None);
+        assert_eq!(settings, upgraded);
+
+        // Running the migrations themselves again doesn't change anything either
+        let map = settings.as_object_mut().unwrap();
+        for migration in MIGRATIONS {
+            migration(map);
+        }
+        assert_eq!(settings, upgraded);
+    }
+
+    #[test]
+    fn keeps_references_to_duplicate_or_missing_names_as_indices() {
+        let mut settings = json!({
+            "processes": [{"name": "same", "ollama": {}}, {"name": "same", "ollama": {}}],
+            "prompts": [{"prompt": "unnamed"}, {"name": "", "prompt": "empty"}],
+            "triggers": [
+                {"process": 1, "prompt": 0, "next_steps": [{"trigger": 5}]},
+                {"process": 0, "prompt": 1, "next_steps": []},
+            ],
+        });
+
+        migrate(&mut settings).unwrap();
+
+        for trigger in settings["triggers"].as_array().unwrap() {
+            assert!(trigger["process"].is_u64());
+            assert!(trigger["prompt"].is_u64());
+        }
+        assert_eq!(settings["triggers"][0]["next_steps"][0]["trigger"], json!(5));
+    }
+
+    #[test]
+    fn names_processes_without_clashing() {
+        let mut settings = json!({
+            "processes": [
+                {"name": "gpt", "ollama": {}},
+                {"command": ["bash", "/scripts/gpt.sh"]},
+                {"command": ["bash", "/other/gpt.sh"]},
+                {"openai": {"model": "gpt-4o"}},
+                {"backend": {"name": "mock"}},
+            ],
+        });
+
+        migrate(&mut settings).unwrap();
+
+        let names = settings["processes"].as_array().unwrap().iter().map(|process| process["name"].clone());
+        assert_eq!(names.collect::<Vec<Value>>(), vec![
+            json!("gpt"), json!("gpt 2"), json!("gpt 3"), json!("gpt-4o"), json!("mock"),
+        ]);
+    }
+
+    #[test]
+    fn refuses_newer_settings() {
+        let mut settings = json!({"version": CURRENT_VERSION + 1});
+        assert!(migrate(&mut settings).unwrap_err().contains("only knows up to"));
+    }
+}
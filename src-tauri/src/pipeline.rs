@@ -0,0 +1,565 @@
+//! Runs triggers, and the triggers they continue with, independent of where their input comes
+//! from and their output goes. The app types and pastes into whatever app has focus through a
+//! `Screen`, `plock run` writes to stdout.
+
+use crate::backends::Event;
+use crate::context::RunContext;
+use crate::conversation;
+use crate::devices::{self, Clipboard, Keyboard};
+use crate::generator::{generate, render_prompt};
+use crate::history::{self, Entry, History};
+use crate::jobs::JobId;
+use crate::settings::{Process, SelectionAction, Settings, Step, Trigger};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+
+/// Where the output of a run goes
+pub trait OutputSink {
+    /// Text of `stream_text_to_screen` steps, as it comes in
+    fn stream_text(&mut self, text: &str) -> Result<(), String>;
+    /// The whole text of `write_final_text_to_screen` steps
+    fn write_text(&mut self, text: &str) -> Result<(), String>;
+    /// The png of `write_image_to_screen` steps
+    fn write_image(&mut self, png: &[u8]) -> Result<(), String>;
+}
+
+/// The app that has focus, text is typed into it and everything else pasted
+pub struct Screen<K, C> {
+    keyboard: K,
+    clipboard: C,
+    /// Put back on the clipboard after pasting
+    restore: Option<String>,
+}
+
+impl<K: Keyboard, C: Clipboard> Screen<K, C> {
+    pub fn new(keyboard: K, clipboard: C) -> Self {
+        Self { keyboard, clipboard, restore: None }
+    }
+
+    /// Puts `clipboard` back after every paste, so the output never stays on the clipboard for
+    /// another run to copy
+    pub fn restoring(mut self, clipboard: Option<&str>) -> Self {
+        self.restore = clipboard.map(String::from);
+        self
+    }
+
+    fn paste(&mut self) -> Result<(), String> {
+        let pasted = self.keyboard.paste();
+        if let Some(restore) = &self.restore {
+            // The app reads the clipboard some time after the keys were pressed
+            thread::sleep(Duration::from_millis(100));
+            self.clipboard.set_text(restore)?;
+        }
+        pasted
+    }
+}
+
+impl<K: Keyboard, C: Clipboard> OutputSink for Screen<K, C> {
+    fn stream_text(&mut self, text: &str) -> Result<(), String> {
+        let _desktop = devices::desktop();
+        self.keyboard.type_text(text)
+    }
+
+    fn write_text(&mut self, text: &str) -> Result<(), String> {
+        let _desktop = devices::desktop();
+        self.clipboard.set_text(text)?;
+        self.paste()
+    }
+
+    fn write_image(&mut self, png: &[u8]) -> Result<(), String> {
+        let _desktop = devices::desktop();
+        self.clipboard.set_image(png)?;
+        self.paste()
+    }
+}
+
+/// Copies the selection of the app that has focus into `$SELECTION`, what the clipboard held
+/// before into `$CLIPBOARD` when it's text, puts that back, and then removes the selection or not
+/// as `selection_action` says. Returns whether it was removed, which it isn't when copying fails.
+pub fn capture(
+    keyboard: &mut dyn Keyboard,
+    clipboard: &mut dyn Clipboard,
+    selection_action: &SelectionAction,
+    context: &mut RunContext,
+) -> Result<bool, String> {
+    let _desktop = devices::desktop();
+    // Nothing to restore when the clipboard holds an image or nothing at all
+    if let Ok(old_clipboard) = clipboard.get_text() {
+        context.set_builtin("CLIPBOARD", old_clipboard);
+    }
+
+    keyboard.copy()?;
+    let selection = clipboard.get_text();
+    restore_clipboard(clipboard, context)?;
+    let selection = selection?;
+    println!("copied... {}", selection);
+    context.set_builtin("SELECTION", selection);
+
+    let removed = match selection_action {
+        SelectionAction::Remove => {
+            keyboard.backspace()?;
+            true
+        }
+        SelectionAction::Newline => {
+            // Why are we deleting and rewriting? something strange with enigo or something is getting locked up
+            keyboard.backspace()?;
+            true
+        }
+        SelectionAction::Nothing => false,
+    };
+    Ok(removed)
+}
+
+/// Puts back what was on the clipboard before the run
+fn restore_clipboard(clipboard: &mut dyn Clipboard, context: &RunContext) -> Result<(), String> {
+    match context.get("CLIPBOARD") {
+        Some(old_clipboard) => clipboard.set_text(old_clipboard),
+        None => Ok(()),
+    }
+}
+
+pub struct Pipeline<'a> {
+    settings: &'a Settings,
+    sink: &'a mut dyn OutputSink,
+    /// Stops the run when set, e.g. by pressing escape
+    cancel: Arc<AtomicBool>,
+    /// Whether `capture` removed the selection
+    removed_selection: bool,
+    /// Whether anything was written to the sink yet
+    wrote: bool,
+    /// Where the triggers that ran are recorded, and the job they're part of
+    history: Option<(History, Option<JobId>)>,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(settings: &'a Settings, sink: &'a mut dyn OutputSink, cancel: Arc<AtomicBool>) -> Self {
+        Self { settings, sink, cancel, removed_selection: false, wrote: false, history: None }
+    }
+
+    /// Records every trigger that runs in `history`
+    pub fn with_history(mut self, history: History, job: Option<JobId>) -> Self {
+        self.history = Some((history, job));
+        self
+    }
+
+    /// Runs the trigger at `index`, and the triggers it continues with, until one doesn't or the run
+    /// is cancelled. `removed_selection` is what `capture` returned, if the selection was captured.
+    pub async fn run(&mut self, index: usize, context: &mut RunContext, removed_selection: bool) -> Result<(), String> {
+        self.removed_selection = removed_selection;
+        self.wrote = false;
+        let mut index = index;
+        let mut is_first_trigger = true;
+
+        loop {
+            let (trigger, prompt, process) = self.settings.resolve_trigger(index)?;
+            let trigger_name = trigger.name.clone().unwrap_or(format!("#{}", index));
+            let process_name = process.name.clone().unwrap_or(process.process_type.backend().0);
+            let started_at = history::now();
+            let started = Instant::now();
+            let mut output = String::new();
+
+            let (rendered_prompt, result) = match render_prompt(prompt.prompt.clone(), context) {
+                Ok(rendered_prompt) => {
+                    let result = self.run_trigger(trigger, process, rendered_prompt.clone(), context, &mut output).await;
+                    (rendered_prompt, result)
+                }
+                Err(e) => (prompt.prompt, Err(format!("Failed to render prompt \"{}\": {}", prompt.name, e))),
+            };
+
+            // Nothing was written yet, so put back what the trigger removed
+            if result.is_err() && is_first_trigger && !self.wrote && removed_selection {
+                if let Err(e) = self.restore_selection(context) {
+                    eprintln!("Failed to restore the selection: {}", e);
+                }
+            }
+            is_first_trigger = false;
+
+            if let Some((history, job)) = &self.history {
+                let entry = Entry {
+                    started_at,
+                    job: *job,
+                    trigger: trigger_name,
+                    process: process_name,
+                    prompt: rendered_prompt,
+                    selection: context.get("SELECTION").unwrap_or_default().to_string(),
+                    output,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                    error: result.as_ref().err().cloned(),
+                    cancelled: self.cancel.load(Ordering::SeqCst),
+                };
+                if let Err(e) = history.record(entry) {
+                    eprintln!("Failed to record the run: {}", e);
+                }
+            }
+
+            match result? {
+                Some(next) => index = next,
+                None => return Ok(()),
+            }
+        }
+    }
+
+    /// Runs a single trigger, returning the index of the trigger to continue with, if any. The text
+    /// it generated is collected in `output`, even when it fails.
+    async fn run_trigger(
+        &mut self,
+        trigger: Trigger,
+        process: Process,
+        rendered_prompt: String,
+        context: &mut RunContext,
+        output: &mut String,
+    ) -> Result<Option<usize>, String> {
+        let history = trigger.conversation.as_deref().map(conversation::history);
+        let mut response_stream = generate(
+            rendered_prompt.clone(),
+            process.process_type,
+            history,
+            context,
+            self.cancel.clone(),
+            trigger.timeout_secs.map(Duration::from_secs),
+        ).await;
+        let streams = trigger.next_steps.iter().any(|step| matches!(step, Step::StreamTextToScreen));
+
+        let mut delta_buffer = Vec::new();
+        let mut image = None;
+
+        while let Some(event) = response_stream.next().await {
+            let response = match event {
+                Event::Text(response) => response,
+                Event::Image(png) => {
+                    image = Some(png);
+                    continue;
+                }
+                Event::SetVar { name, value } => {
+                    context.set_output(name, value);
+                    continue;
+                }
+                Event::Progress(message) => {
+                    eprintln!("Progress: {}", message);
+                    continue;
+                }
+                Event::Error(e) => return Err(e),
+                Event::Cancelled => return Ok(None),
+                Event::TimedOut(timeout) => return Err(format!("Timed out after {}s", timeout.as_secs())),
+            };
+            output.push_str(&response);
+            delta_buffer.push(response);
+
+            if streams && delta_buffer.len() > 4 {
+                let delta_output = delta_buffer.join("");
+                delta_buffer.clear();
+                if !self.stream(&delta_output)? {
+                    return Ok(None);
+                }
+            }
+        }
+
+        let delta_output = delta_buffer.join("");
+        let whole_output = output.clone();
+
+        if let Some(session) = trigger.conversation.as_deref() {
+            conversation::record(session, rendered_prompt, whole_output.clone());
+        }
+
+        let mut next_index = None;
+        for step in trigger.next_steps {
+            match step {
+                Step::StreamTextToScreen => {
+                    if !delta_output.is_empty() && !self.stream(&delta_output)? {
+                        return Ok(None);
+                    }
+                }
+                Step::StoreAsEnvVar(key) => {
+                    context.set_output(key, whole_output.clone());
+                }
+                Step::Trigger(next) => {
+                    let next = self
+                        .settings
+                        .trigger_index(&next)
+                        .ok_or(format!("There is no trigger {} to continue with", next))?;
+                    next_index = Some(next);
+                }
+                Step::ResetConversation(session) => {
+                    conversation::reset(&session);
+                }
+                Step::ForkConversation { from, into } => {
+                    conversation::fork(&from, &into);
+                }
+                Step::WriteFinalTextToScreen => {
+                    let mut final_output = whole_output.clone();
+                    // The selection was removed to be written again along with the output, see `capture`
+                    let selection_action = trigger.selection_action.clone().unwrap_or(SelectionAction::Remove);
+                    if self.removed_selection && matches!(selection_action, SelectionAction::Newline) {
+                        let selection = context.get("SELECTION").unwrap_or_default();
+                        final_output.insert_str(0, &format!("{}\n\n", selection));
+                    }
+                    self.wrote = true;
+                    self.sink.write_text(&final_output)?;
+                }
+                Step::WriteImageToScreen => {
+                    // Commands without the ndjson protocol write the image as base64
+                    let png = match image.clone() {
+                        Some(png) => png,
+                        None => STANDARD
+                            .decode(whole_output.trim())
+                            .map_err(|e| format!("Failed to decode the image: {}", e))?,
+                    };
+                    self.wrote = true;
+                    self.sink.write_image(&png)?;
+                }
+            }
+        }
+
+        Ok(next_index)
+    }
+
+    /// Streams `text` to the sink a bit at a time, returning whether to go on, which is not when the
+    /// run was cancelled in the meantime
+    fn stream(&mut self, text: &str) -> Result<bool, String> {
+        for chunk in text.chars().collect::<Vec<char>>().chunks(19) {
+            self.wrote = true;
+            self.sink.stream_text(&String::from_iter(chunk))?;
+            if self.cancel.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn restore_selection(&mut self, context: &RunContext) -> Result<(), String> {
+        let selection = context.get("SELECTION").unwrap_or_default();
+        if selection.is_empty() {
+            return Ok(());
+        }
+        self.sink.write_text(selection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::fake::{Fake, Recorded};
+    use serde_json::json;
+
+    /// Settings with a `mock` process answering `response`, a prompt and the given triggers
+    fn settings(response: &str, triggers: serde_json::Value) -> Settings {
+        serde_json::from_value(json!({
+            "version": 0,
+            "environment": {},
+            "processes": [{"backend": {"name": "mock", "options": {"response": response}}}],
+            "prompts": [{"name": "fix", "prompt": "Fix ${SELECTION}"}],
+            "triggers": triggers,
+        }))
+        .unwrap()
+    }
+
+    async fn run(settings: &Settings, desktop: &Fake, context: &mut RunContext, removed_selection: bool) -> Result<(), String> {
+        let mut screen = Screen::new(desktop.clone(), desktop.clone());
+        let mut pipeline = Pipeline::new(settings, &mut screen, Arc::new(AtomicBool::new(false)));
+        pipeline.run(0, context, removed_selection).await
+    }
+
+    fn context(selection: &str) -> RunContext {
+        let mut context = RunContext::new(Default::default());
+        context.set_builtin("SELECTION", selection.to_string());
+        context
+    }
+
+    #[test]
+    fn captures_and_removes_the_selection() {
+        let mut desktop = Fake::new("text", "old");
+        let mut context = RunContext::new(Default::default());
+
+        let removed = capture(&mut desktop.clone(), &mut desktop, &SelectionAction::Remove, &mut context).unwrap();
+
+        assert!(removed);
+        assert_eq!(context.get("SELECTION"), Some("text"));
+        assert_eq!(context.get("CLIPBOARD"), Some("old"));
+        assert_eq!(desktop.recorded(), vec![
+            Recorded::Copy,
+            Recorded::SetText("old".to_string()),
+            Recorded::Backspace,
+        ]);
+        assert_eq!(desktop.clipboard(), "old");
+    }
+
+    #[test]
+    fn captures_and_keeps_the_selection() {
+        let mut desktop = Fake::new("text", "old");
+        let mut context = RunContext::new(Default::default());
+
+        let removed = capture(&mut desktop.clone(), &mut desktop, &SelectionAction::Nothing, &mut context).unwrap();
+
+        assert!(!removed);
+        assert_eq!(context.get("SELECTION"), Some("text"));
+        assert_eq!(desktop.recorded(), vec![Recorded::Copy, Recorded::SetText("old".to_string())]);
+    }
+
+    #[test]
+    fn leaves_a_clipboard_without_text_alone() {
+        let mut desktop = Fake::new("text", "").with_unreadable_clipboard();
+        let mut context = RunContext::new(Default::default());
+
+        capture(&mut desktop.clone(), &mut desktop, &SelectionAction::Nothing, &mut context).unwrap();
+
+        assert_eq!(context.get("SELECTION"), Some("text"));
+        assert_eq!(context.get("CLIPBOARD"), None);
+        assert_eq!(desktop.recorded(), vec![Recorded::Copy]);
+    }
+
+    #[test]
+    fn leaves_the_selection_when_it_cant_be_copied() {
+        // Copying what isn't text, like an image, leaves nothing on the clipboard to read
+        let mut desktop = Fake::new("", "").with_unreadable_clipboard();
+        let mut context = RunContext::new(Default::default());
+
+        let result = capture(&mut desktop.clone(), &mut desktop, &SelectionAction::Remove, &mut context);
+
+        assert!(result.is_err());
+        assert_eq!(context.get("SELECTION"), None);
+        assert_eq!(desktop.recorded(), vec![Recorded::Copy]);
+    }
+
+    #[test]
+    fn restores_the_clipboard() {
+        let mut desktop = Fake::new("", "output");
+        let mut context = RunContext::new(Default::default());
+        context.set_builtin("CLIPBOARD", "old".to_string());
+
+        restore_clipboard(&mut desktop, &context).unwrap();
+
+        assert_eq!(desktop.clipboard(), "old");
+    }
+
+    #[test]
+    fn puts_back_the_clipboard_after_pasting() {
+        let desktop = Fake::new("", "");
+        let mut screen = Screen::new(desktop.clone(), desktop.clone()).restoring(Some("old"));
+
+        screen.write_text("output").unwrap();
+
+        assert_eq!(desktop.written(), "output");
+        assert_eq!(desktop.clipboard(), "old");
+    }
+
+    #[tokio::test]
+    async fn streams_the_output_as_typed_text() {
+        let settings = settings("a fixed sentence", json!([
+            {"process": 0, "prompt": 0, "next_steps": ["stream_text_to_screen"]},
+        ]));
+        let desktop = Fake::new("", "");
+
+        run(&settings, &desktop, &mut context("text"), true).await.unwrap();
+
+        assert_eq!(desktop.written(), "a fixed sentence");
+        assert!(desktop.recorded().iter().all(|recorded| matches!(recorded, Recorded::Type(_))));
+    }
+
+    #[tokio::test]
+    async fn pastes_the_final_text() {
+        let settings = settings("fixed", json!([
+            {"process": 0, "prompt": 0, "next_steps": ["write_final_text_to_screen"]},
+        ]));
+        let desktop = Fake::new("", "");
+
+        run(&settings, &desktop, &mut context("text"), true).await.unwrap();
+
+        assert_eq!(desktop.recorded(), vec![Recorded::SetText("fixed".to_string()), Recorded::Paste]);
+    }
+
+    #[tokio::test]
+    async fn writes_the_selection_again_before_the_output_for_newline() {
+        let settings = settings("fixed", json!([
+            {
+                "process": 0,
+                "prompt": 0,
+                "next_steps": ["write_final_text_to_screen"],
+                "selection_action": "newline",
+            },
+        ]));
+        let desktop = Fake::new("", "");
+
+        run(&settings, &desktop, &mut context("text"), true).await.unwrap();
+
+        assert_eq!(desktop.written(), "text\n\nfixed");
+    }
+
+    #[tokio::test]
+    async fn continues_with_the_next_trigger() {
+        let mut settings = settings("", json!([
+            {"process": 0, "prompt": 0, "next_steps": [{"store_as_env_var": "FIXED"}, {"trigger": "second"}]},
+            {"name": "second", "process": 0, "prompt": 1, "next_steps": ["stream_text_to_screen"]},
+        ]));
+        settings.processes[0] = serde_json::from_value(json!({"backend": {"name": "mock", "options": {}}})).unwrap();
+        settings.prompts.push(serde_json::from_value(json!({"name": "again", "prompt": "Again ${FIXED}"})).unwrap());
+        let desktop = Fake::new("", "");
+
+        run(&settings, &desktop, &mut context("text"), false).await.unwrap();
+
+        // The mock answers with the prompt itself
+        assert_eq!(desktop.written(), "Again Fix text");
+    }
+
+    #[tokio::test]
+    async fn puts_back_the_selection_when_the_process_fails() {
+        let mut settings = settings("", json!([
+            {"process": 0, "prompt": 0, "next_steps": ["stream_text_to_screen"]},
+        ]));
+        settings.processes[0] = serde_json::from_value(json!({"backend": {"name": "missing", "options": {}}})).unwrap();
+        let desktop = Fake::new("", "");
+
+        let result = run(&settings, &desktop, &mut context("text"), true).await;
+
+        assert!(result.is_err());
+        assert_eq!(desktop.written(), "text");
+    }
+
+    #[tokio::test]
+    async fn puts_back_the_selection_when_the_prompt_fails_to_render() {
+        let mut settings = settings("fixed", json!([
+            {"process": 0, "prompt": 0, "next_steps": ["stream_text_to_screen"]},
+        ]));
+        settings.prompts[0].prompt = "Fix ${MISSING}".to_string();
+        let desktop = Fake::new("", "");
+
+        let result = run(&settings, &desktop, &mut context("text"), true).await;
+
+        assert!(result.is_err());
+        assert_eq!(desktop.written(), "text");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn puts_back_the_selection_when_the_process_fails_before_anything_was_written() {
+        let mut settings = settings("", json!([
+            {"process": 0, "prompt": 0, "next_steps": ["write_final_text_to_screen"]},
+        ]));
+        settings.processes[0] = serde_json::from_value(json!({
+            "command": {"command": ["sh", "-c", "echo partial; exit 1"], "input_mode": "stdin"},
+        })).unwrap();
+        let desktop = Fake::new("", "");
+
+        let result = run(&settings, &desktop, &mut context("text"), true).await;
+
+        assert!(result.is_err());
+        assert_eq!(desktop.written(), "text");
+    }
+
+    #[tokio::test]
+    async fn keeps_what_was_written_when_the_run_fails_afterwards() {
+        let settings = settings("fixed", json!([
+            {"process": 0, "prompt": 0, "next_steps": ["write_final_text_to_screen", {"trigger": "missing"}]},
+        ]));
+        let desktop = Fake::new("", "");
+
+        let result = run(&settings, &desktop, &mut context("text"), true).await;
+
+        assert!(result.is_err());
+        assert_eq!(desktop.written(), "fixed");
+    }
+}
@@ -0,0 +1,118 @@
+//! Named alternatives to `settings.json`, kept as `profiles/<name>.json` next to it, or in any
+//! other format settings can be in.
+//!
+//! A profile is either complete settings of its own, or an overlay holding `"overlay": true`
+//! and only what differs from `settings.json`, which is merged on top of it:
+//!
+//! - `environment` variables are added, replacing those with the same name
+//! - `processes`, `prompts` and `triggers` replace those with the same name, and are added after
+//!   the others otherwise
+//! - `prompt_dirs` are added
+//! - anything else replaces what's in `settings.json`
+
+use crate::formats::{find_file, EXTENSIONS};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PROFILES_DIR: &str = "profiles";
+/// Holds the name of the profile in use, none means `settings.json` is
+const ACTIVE_PROFILE_FILE: &str = "active_profile";
+
+/// Lists that are merged by the names of their items
+const NAMED_LISTS: [&str; 3] = ["processes", "prompts", "triggers"];
+
+/// The names of the profiles in `settings_dir`, sorted
+pub fn list_profiles(settings_dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(settings_dir.join(PROFILES_DIR)) else {
+        return Vec::new();
+    };
+
+    let mut profiles = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file() && path.extension().is_some_and(|extension| {
+                EXTENSIONS.iter().any(|allowed| extension == *allowed)
+            })
+        })
+        .filter_map(|path| path.file_stem().map(|stem| stem.to_string_lossy().to_string()))
+        .filter(|name| !is_backup(name))
+        .collect::<Vec<String>>();
+    profiles.sort();
+    profiles.dedup();
+    profiles
+}
+
+pub fn profile_path(settings_dir: &Path, name: &str) -> PathBuf {
+    find_file(&settings_dir.join(PROFILES_DIR), name)
+}
+
+/// The profile in use, if it still exists
+pub fn active_profile(settings_dir: &Path) -> Option<String> {
+    let name = fs::read_to_string(settings_dir.join(ACTIVE_PROFILE_FILE)).ok()?;
+    let name = name.trim();
+    if name.is_empty() {
+        return None;
+    }
+    if !profile_path(settings_dir, name).is_file() {
+        eprintln!("Profile \"{}\" doesn't exist anymore, using settings.json", name);
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Makes `name` the profile in use from now on, or `settings.json` when `None`
+pub fn set_active_profile(settings_dir: &Path, name: Option<&str>) -> Result<(), String> {
+    if let Some(name) = name {
+        if !profile_path(settings_dir, name).is_file() {
+            return Err(format!("There is no profile \"{}\"", name));
+        }
+    }
+    fs::write(settings_dir.join(ACTIVE_PROFILE_FILE), name.unwrap_or_default())
+        .map_err(|e| format!("Failed to store the active profile: {}", e))
+}
+
+/// The overlay in `profile`, or `None` when it's complete settings
+pub fn as_overlay(mut profile: Value) -> Option<Value> {
+    let overlay = profile.as_object_mut()?.remove("overlay");
+    overlay.is_some_and(|overlay| overlay == Value::Bool(true)).then_some(profile)
+}
+
+/// Merges `overlay` into `base`, see the module docs for how
+pub fn merge(base: &mut Value, overlay: Value) {
+    let (Some(base), Value::Object(overlay)) = (base.as_object_mut(), overlay) else {
+        return;
+    };
+
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(environment)), Value::Object(overlay)) if key == "environment" => {
+                environment.extend(overlay);
+            }
+            (Some(Value::Array(dirs)), Value::Array(overlay)) if key == "prompt_dirs" => {
+                dirs.extend(overlay);
+            }
+            (Some(Value::Array(items)), Value::Array(overlay)) if NAMED_LISTS.contains(&key.as_str()) => {
+                for item in overlay {
+                    let existing = item["name"].as_str().and_then(|name| {
+                        items.iter().position(|other| other["name"].as_str() == Some(name))
+                    });
+                    match existing {
+                        Some(i) => items[i] = item,
+                        None => items.push(item),
+                    }
+                }
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Whether `name` is a copy made before migrating a profile, e.g. `coding.v1.1729000000`
+fn is_backup(name: &str) -> bool {
+    let parts = name.rsplitn(3, '.').collect::<Vec<&str>>();
+    let is_number = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    parts.len() == 3 && is_number(parts[0]) && parts[1].strip_prefix('v').is_some_and(is_number)
+}
@@ -0,0 +1,92 @@
+//! Prompts kept in Markdown or text files, optionally starting with front-matter:
+//!
+//! ```text
+//! ---
+//! name: Rewrite
+//! description: Rewrites the selection in a friendlier tone
+//! process: gpt
+//! ---
+//! Rewrite the following text ...
+//! ```
+
+use crate::settings::{CustomPrompt, Reference};
+use std::fs;
+use std::path::Path;
+
+const EXTENSIONS: [&str; 3] = ["md", "markdown", "txt"];
+
+/// Loads a single prompt file, named after the file when the front-matter doesn't name it
+pub fn load_prompt_file(path: &Path) -> Result<CustomPrompt, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read prompt {}: {}", path.display(), e))?;
+    let (front_matter, body) = split_front_matter(&text);
+
+    let mut prompt = CustomPrompt {
+        name: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+        prompt: body.trim().to_string(),
+        file: Some(path.to_path_buf()),
+        description: None,
+        process: None,
+    };
+    for (key, value) in front_matter {
+        match key {
+            "name" => prompt.name = value.to_string(),
+            "description" => prompt.description = Some(value.to_string()),
+            "process" => {
+                prompt.process = Some(match value.parse::<usize>() {
+                    Ok(index) => Reference::Index(index),
+                    Err(_) => Reference::Name(value.to_string()),
+                });
+            }
+            _ => eprintln!("Ignoring unknown front-matter `{}` in prompt {}", key, path.display()),
+        }
+    }
+
+    Ok(prompt)
+}
+
+/// Loads every prompt file directly inside `dir`, ordered by file name
+pub fn load_prompt_dir(dir: &Path) -> Result<Vec<CustomPrompt>, String> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| format!("Failed to read prompt directory {}: {}", dir.display(), e))?;
+
+    let mut paths = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file() && path.extension().is_some_and(|extension| {
+                EXTENSIONS.iter().any(|allowed| extension.eq_ignore_ascii_case(allowed))
+            })
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    paths.iter().map(|path| load_prompt_file(path)).collect()
+}
+
+/// Splits `key: value` front-matter between `---` lines from the rest of the file
+fn split_front_matter(text: &str) -> (Vec<(&str, &str)>, &str) {
+    let Some(rest) = text.strip_prefix("---\n").or_else(|| text.strip_prefix("---\r\n")) else {
+        return (Vec::new(), text);
+    };
+
+    let mut front_matter = Vec::new();
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        offset += line.len();
+        let line = line.trim();
+        if line == "---" {
+            return (front_matter, &rest[offset..]);
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value);
+            front_matter.push((key.trim(), value));
+        }
+    }
+
+    // No closing `---`, so it wasn't front-matter after all
+    (Vec::new(), text)
+}
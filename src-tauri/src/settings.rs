@@ -1,54 +1,169 @@
-use serde::{Deserialize, Serialize};
-use std::{env, fs, path::Path};
-use std::collections::HashMap;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{Schema, SchemaObject};
+use schemars::{schema_for, JsonSchema};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::{fs, path::Path};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use lazy_static::lazy_static;
-use tauri::{command, AppHandle, GlobalShortcutManager};
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+use tauri::api::path::app_local_data_dir;
+use tauri::{command, AppHandle, Config, GlobalShortcutManager};
+use crate::formats::{candidates, find_file, Format};
+use crate::includes::resolve_includes;
+use crate::jobs::Jobs;
+use crate::migrations::{migrate, CURRENT_VERSION};
+use crate::profiles::{active_profile, as_overlay, list_profiles, merge, profile_path, set_active_profile};
+use crate::prompts::{load_prompt_dir, load_prompt_file};
 use crate::settings::Step::StreamTextToScreen;
+use crate::validation::{validate, Problem, Severity};
+
+/// Written next to the settings file, for editors to complete and check it with
+const SCHEMA_FILE: &str = "settings.schema.json";
 
 lazy_static! {
     pub static ref SETTINGS: Mutex<Settings> = Mutex::new(Settings::default());
+    static ref WATCHER: Mutex<Option<SettingsWatcher>> = Mutex::new(None);
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct Settings {
+    /// Points editors at the schema of the settings, which is written next to them
+    #[serde(rename = "$schema", default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    /// Which `migrations` the settings are up to date with
+    pub version: u64,
+    /// Settings files, in any format, these settings are merged on top of, see `includes`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<PathBuf>,
     pub environment: HashMap<String, String>,
-    pub processes: Vec<ProcessType>,
+    pub processes: Vec<Process>,
     pub prompts: Vec<CustomPrompt>,
+    /// Directories of prompt files, their prompts come after `prompts`
+    #[serde(default)]
+    pub prompt_dirs: Vec<PathBuf>,
     pub triggers: Vec<Trigger>,
+    /// How much of the history of runs is kept, see `history`
+    #[serde(default)]
+    pub history: HistorySettings,
 }
 
 impl Settings {
-    pub fn add_env_var(&mut self, key: String, value: String) {
-        env::set_var(key.clone(), value.clone());
-        self.environment.insert(key, value);
+    /// Loads prompts kept in files, paths are relative to `base`, the directory of the settings file
+    pub fn load_prompt_files(&mut self, base: &Path) -> Result<(), String> {
+        for prompt in self.prompts.iter_mut() {
+            let Some(file) = prompt.file.clone() else {
+                continue;
+            };
+            let loaded = load_prompt_file(&base.join(file))?;
+            // Anything set in the settings wins over the front-matter
+            if prompt.name.is_empty() {
+                prompt.name = loaded.name;
+            }
+            prompt.description = prompt.description.take().or(loaded.description);
+            prompt.process = prompt.process.take().or(loaded.process);
+            prompt.prompt = loaded.prompt;
+        }
+
+        for dir in self.prompt_dirs.clone() {
+            self.prompts.extend(load_prompt_dir(&base.join(dir))?);
+        }
+        Ok(())
+    }
+
+    /// The trigger at `index` along with its prompt and process
+    pub fn resolve_trigger(&self, index: usize) -> Result<(Trigger, CustomPrompt, Process), String> {
+        let trigger = self.triggers.get(index).ok_or(format!("There is no trigger #{}", index))?;
+        let label = trigger.label(index);
+
+        let prompt = trigger.prompt
+            .resolve(&self.prompts, |prompt| Some(prompt.name.as_str()))
+            .map(|i| &self.prompts[i])
+            .ok_or(format!("Trigger {} uses prompt {}, which doesn't exist", label, trigger.prompt))?;
+
+        let process = trigger.process.as_ref().or(prompt.process.as_ref()).ok_or(format!(
+            "Trigger {} has no process, and prompt \"{}\" has no default one", label, prompt.name,
+        ))?;
+        let process = process
+            .resolve(&self.processes, |process| process.name.as_deref())
+            .map(|i| &self.processes[i])
+            .ok_or(format!("Trigger {} uses process {}, which doesn't exist", label, process))?;
+
+        Ok((trigger.clone(), prompt.clone(), process.clone()))
+    }
+
+    pub fn trigger_index(&self, trigger: &Reference) -> Option<usize> {
+        trigger.resolve(&self.triggers, |trigger| trigger.name.as_deref())
+    }
+}
+
+/// Points at a process, prompt or trigger, by its name or by its position in the list
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Reference {
+    Index(usize),
+    Name(String),
+}
+
+impl Reference {
+    /// The position of what this points at in `items`
+    pub fn resolve<T>(&self, items: &[T], name: impl Fn(&T) -> Option<&str>) -> Option<usize> {
+        match self {
+            Reference::Index(i) => (*i < items.len()).then_some(*i),
+            Reference::Name(wanted) => items.iter().position(|item| name(item) == Some(wanted.as_str())),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Index(i) => write!(f, "#{}", i),
+            Reference::Name(name) => write!(f, "\"{}\"", name),
+        }
     }
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
+            schema: Some(SCHEMA_FILE.to_string()),
+            version: CURRENT_VERSION,
+            include: Vec::new(),
             environment: HashMap::from([
                 ("OLLAMA_MODEL".to_string(), "openhermes2.5-mistral".to_string())
             ]),
             processes: vec![
-                ProcessType::Ollama,
-                ProcessType::Command(
-                    ["bash", "/path/to/gpt.sh"].iter().map(|s| s.to_string()).collect()
-                ),
+                Process {
+                    name: Some("ollama".to_string()),
+                    process_type: ProcessType::Ollama(Ollama::default()),
+                },
+                Process {
+                    name: Some("gpt".to_string()),
+                    process_type: ProcessType::Command(CommandProcess {
+                        command: ["bash", "/path/to/gpt.sh"].iter().map(|s| s.to_string()).collect(),
+                        ..CommandProcess::default()
+                    }),
+                },
             ],
             prompts: vec![
                 CustomPrompt {
                     name: "default basic".to_string(),
                     prompt: "$SELECTION".to_string(),
+                    ..CustomPrompt::default()
                 },
                 CustomPrompt {
                     name: "default with context".to_string(),
                     prompt: "I will ask you to do something. Below is some extra context to help do what I ask. --------- $CLIPBOARD --------- Given the above context, please, $SELECTION. DO NOT OUTPUT ANYTHING ELSE.".to_string(),
+                    ..CustomPrompt::default()
                 },
             ],
+            prompt_dirs: vec![],
             triggers: vec![
                 Trigger {
                     trigger_with_shortcut: Some({
@@ -62,8 +177,9 @@ impl Default for Settings {
                             "Ctrl+Shift+.".to_string()
                         }
                     }),
-                    process: 0,
-                    prompt: 0,
+                    name: Some("basic".to_string()),
+                    process: Some(Reference::Name("ollama".to_string())),
+                    prompt: Reference::Name("default basic".to_string()),
                     next_steps: vec![StreamTextToScreen],
                     ..Trigger::default()
                 },
@@ -79,76 +195,345 @@ impl Default for Settings {
                             "Ctrl+Shift+/".to_string()
                         }
                     }),
-                    process: 0,
-                    prompt: 1,
+                    name: Some("with context".to_string()),
+                    process: Some(Reference::Name("ollama".to_string())),
+                    prompt: Reference::Name("default with context".to_string()),
                     next_steps: vec![StreamTextToScreen],
                     ..Trigger::default()
                 },
             ],
+            history: HistorySettings::default(),
         }
     }
 }
 
-#[command]
-pub fn save_settings(app_handle: AppHandle, settings: &Settings) -> Result<(), String> {
-    let path = get_settings_path(app_handle)?;
-    let data = serde_json::to_string_pretty(settings).map_err(|e| e.to_string())?;
-    fs::write(path, data).map_err(|e| e.to_string())?;
-    Ok(())
+/// Writes `settings` to `path` in the format its extension says
+fn write_settings<T: Serialize>(path: &Path, settings: &T) -> Result<(), String> {
+    let data = Format::of(path).write(settings)?;
+    // Leaves the file alone when nothing changed, which would otherwise trigger a reload
+    if fs::read_to_string(path).is_ok_and(|existing| existing == data) {
+        return Ok(());
+    }
+    fs::write(path, data).map_err(|e| e.to_string())
 }
 
-#[command]
-pub fn load_settings(
-    app_handle: AppHandle, trigger_index: Arc<AtomicUsize>, trigger_flag: Arc<AtomicBool>
-) -> Result<(), String> {
-    let path = get_settings_path(app_handle.clone())?;
-    let settings = if path.exists() {
-        let data = fs::read_to_string(path).map_err(|e| e.to_string())?;
-        serde_json::from_str(&data).map_err(|e| e.to_string())?
-    } else {
-        Settings::default()
-    };
-    for (key, value) in settings.environment.iter() {
-        { SETTINGS.lock().unwrap().add_env_var(key.clone(), value.clone()); }
+/// Writes the settings file at `source` back after loading it, pointing at the schema and
+/// upgraded when it was migrated. Files that don't include others also get the fields newer
+/// versions added, while files that do are written as they are, as every field they hold
+/// overrides the included files.
+fn write_back(path: &Path, source: &Path, mut stored: Value, migrated_from: Option<u64>) -> Result<(), String> {
+    let format = Format::of(source);
+    // Other formats are only written when they have to be, as that loses any comments in them
+    if format != Format::Json && migrated_from.is_none() {
+        return Ok(());
+    }
+    if let Some(fields) = stored.as_object_mut().filter(|_| format == Format::Json) {
+        if !fields.contains_key("$schema") {
+            // Profiles are a directory further down
+            let relative = if source == path { SCHEMA_FILE.to_string() } else { format!("../{}", SCHEMA_FILE) };
+            fields.insert("$schema".to_string(), Value::String(relative));
+        }
     }
 
-    let app_handle_clone = app_handle.clone();
-    // Ensures any newly introduced fields are stored in the settings file
-    save_settings(app_handle, &settings)?;
-    *SETTINGS.lock().unwrap() = settings;
+    if stored.get("include").is_some() {
+        return write_settings(source, &stored);
+    }
+    let settings: Settings = serde_json::from_value(stored).map_err(|e| format!("{}: {}", file_name(source), e))?;
+    write_settings(source, &settings)
+}
 
-    let triggers_clone = {
-        let settings = SETTINGS.lock().unwrap();
-        settings.triggers.clone()
+/// Settings read from their files, ready to be put in use
+struct Loaded {
+    /// As they are in `source`, upgraded to the current version, which is what gets written back
+    stored: Value,
+    source: PathBuf,
+    migrated_from: Option<u64>,
+    /// Along with includes, the overlay and prompt files, which is what gets used
+    settings: Settings,
+    warnings: Vec<Problem>,
+}
+
+/// Reads the settings file at `path`, or the active profile next to it, and checks them.
+/// Problems that keep the settings from being used are returned as the error. Every file the
+/// settings are read from is added to `files`, also the ones read before something went wrong.
+fn read_all(path: &Path, files: &mut Vec<PathBuf>) -> Result<Loaded, String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    // Any of them showing up in another format replaces them
+    files.extend(candidates(dir, "settings"));
+    if let Some(profile) = active_profile(dir) {
+        files.push(profile_path(dir, &profile));
+    }
+    let (source, overlay) = settings_source(path)?;
+    files.push(source.clone());
+    let (stored, migrated_from) = read_settings(&source)?;
+
+    // Loaded into a copy, so includes, overlays and prompts from files aren't copied into the settings
+    // file. The file as it's written is merged in, so fields it leaves out don't override includes.
+    let mut merged = resolve_includes(stored.clone(), &source, files)?;
+    if let Some((overlay_path, overlay)) = overlay {
+        merge(&mut merged, resolve_includes(overlay, &overlay_path, files)?);
+    }
+    let mut settings: Settings = serde_json::from_value(merged).map_err(|e| format!("{}: {}", file_name(&source), e))?;
+    settings.load_prompt_files(dir)?;
+    let (errors, warnings): (Vec<Problem>, Vec<Problem>) = validate(&settings)
+        .into_iter()
+        .partition(|problem| problem.severity == Severity::Error);
+    if !errors.is_empty() {
+        let errors = errors.iter().map(|error| error.to_string()).collect::<Vec<String>>();
+        return Err(errors.join("\n"));
+    }
+
+    Ok(Loaded { stored, source, migrated_from, settings, warnings })
+}
+
+/// Reads the settings at `path` like `load_settings` does, but without writing to any file or
+/// putting them in use. Returns them along with any warnings.
+pub fn load_settings_file(path: &Path) -> Result<(Settings, Vec<Problem>), String> {
+    let Loaded { settings, warnings, .. } = read_all(path, &mut Vec::new())?;
+    Ok((settings, warnings))
+}
+
+/// Loads the settings file and puts it in use, unless `validation` finds errors in it. Those keep
+/// the settings in use as they are and are returned as the error, otherwise any warnings are.
+#[command]
+pub fn load_settings(app_handle: AppHandle, jobs: Arc<Jobs>) -> Result<Vec<Problem>, String> {
+    let path = get_settings_path(app_handle.clone())?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut files = Vec::new();
+    let Loaded { stored, source, migrated_from, settings: loaded, mut warnings } = match read_all(&path, &mut files) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            // So saving a fix is picked up, even when the settings couldn't be loaded at all yet
+            keep_watching(files);
+            return Err(e);
+        }
     };
 
-    app_handle_clone.global_shortcut_manager()
+    if let Err(e) = write_schema(&path.with_file_name(SCHEMA_FILE)) {
+        eprintln!("Failed to write the settings schema: {}", e);
+    }
+    if let Some(version) = migrated_from {
+        let backup = backup_settings(&source, version)?;
+        println!("Migrated settings from version {}, the old ones are in {}", version, backup.display());
+    }
+    write_back(&path, &source, stored, migrated_from)?;
+
+    app_handle.global_shortcut_manager()
         .unregister_all()
-        .expect("Failed to register global shortcut");
+        .map_err(|e| format!("Failed to unregister shortcuts: {}", e))?;
+
+    watch_files(files, dir, &loaded);
+    let triggers_clone = loaded.triggers.clone();
+    *SETTINGS.lock().unwrap() = loaded;
 
     for (i, trigger) in triggers_clone.iter().enumerate() {
         if let Some(shortcut) = trigger.trigger_with_shortcut.clone() {
+            let jobs = jobs.clone();
+            let registered = app_handle.global_shortcut_manager()
+                .register(&shortcut, move || jobs.request(i));
+            if let Err(e) = registered {
+                warnings.push(Problem {
+                    severity: Severity::Warning,
+                    path: format!("triggers[{}].trigger_with_shortcut", i),
+                    message: format!("Failed to register `{}`: {}", shortcut, e),
+                });
+            }
+        }
+    }
+
+    Ok(warnings)
+}
+
+/// The files `load_settings` reads, and the directories they're watched through. Directories are
+/// watched rather than the files themselves, as editors often save by replacing the file.
+struct SettingsWatcher {
+    debouncer: Debouncer<RecommendedWatcher>,
+    dirs: HashSet<PathBuf>,
+    files: HashSet<PathBuf>,
+    prompt_dirs: HashSet<PathBuf>,
+}
 
-            let trigger_index_clone = trigger_index.clone();
-            let trigger_flag_second_clone = trigger_flag.clone();
+impl SettingsWatcher {
+    fn matters(&self, path: &Path) -> bool {
+        self.files.contains(path) || path.parent().is_some_and(|dir| self.prompt_dirs.contains(dir))
+    }
+}
 
-            app_handle_clone.global_shortcut_manager()
-                .register(&shortcut, move || {
-                    trigger_index_clone.store(i, Ordering::SeqCst);
-                    trigger_flag_second_clone.store(true, Ordering::SeqCst);
+/// Calls `on_change` whenever the settings file or a prompt file changes, once things settle down.
+/// Which files are watched follows along with every `load_settings`.
+pub fn watch_settings(on_change: impl Fn() + Send + 'static) -> Result<(), String> {
+    let debouncer = new_debouncer(Duration::from_millis(500), move |events: DebounceEventResult| {
+        let changed = match events {
+            Ok(events) => {
+                let watcher = WATCHER.lock().unwrap();
+                watcher.as_ref().is_some_and(|watcher| {
+                    events.iter().any(|event| watcher.matters(&event.path))
                 })
-                .expect("Failed to register global shortcut");
+            }
+            Err(e) => {
+                eprintln!("Error watching settings: {}", e);
+                false
+            }
+        };
+        if changed {
+            on_change();
         }
-    }
+    }).map_err(|e| format!("Failed to watch settings: {}", e))?;
 
+    *WATCHER.lock().unwrap() = Some(SettingsWatcher {
+        debouncer,
+        dirs: HashSet::new(),
+        files: HashSet::new(),
+        prompt_dirs: HashSet::new(),
+    });
     Ok(())
 }
 
+/// Points the watcher at `files`, the settings files `settings` were loaded from, and at the prompt
+/// files they refer to, relative to `base`
+fn watch_files(files: Vec<PathBuf>, base: &Path, settings: &Settings) {
+    let mut watcher = WATCHER.lock().unwrap();
+    let Some(watcher) = watcher.as_mut() else {
+        return;
+    };
+
+    let mut files = files.into_iter().collect::<HashSet<PathBuf>>();
+    files.extend(settings.prompts.iter().filter_map(|prompt| prompt.file.as_ref()).map(|file| base.join(file)));
+    let prompt_dirs = settings.prompt_dirs.iter().map(|dir| base.join(dir)).collect::<HashSet<PathBuf>>();
+
+    watch(watcher, files, prompt_dirs);
+}
+
+/// Adds `files` to what's watched, keeping what was, as it isn't known what else settings that
+/// failed to load refer to
+fn keep_watching(files: Vec<PathBuf>) {
+    let mut watcher = WATCHER.lock().unwrap();
+    let Some(watcher) = watcher.as_mut() else {
+        return;
+    };
+
+    let mut files = files.into_iter().collect::<HashSet<PathBuf>>();
+    files.extend(watcher.files.iter().cloned());
+    let prompt_dirs = watcher.prompt_dirs.clone();
+    watch(watcher, files, prompt_dirs);
+}
+
+fn watch(watcher: &mut SettingsWatcher, files: HashSet<PathBuf>, prompt_dirs: HashSet<PathBuf>) {
+    let mut dirs = files.iter().filter_map(|file| file.parent()).map(Path::to_path_buf).collect::<HashSet<_>>();
+    dirs.extend(prompt_dirs.iter().cloned());
+
+    for dir in watcher.dirs.difference(&dirs) {
+        let _ = watcher.debouncer.watcher().unwatch(dir);
+    }
+    for dir in dirs.difference(&watcher.dirs) {
+        if let Err(e) = watcher.debouncer.watcher().watch(dir, RecursiveMode::NonRecursive) {
+            eprintln!("Failed to watch {}: {}", dir.display(), e);
+        }
+    }
+
+    watcher.dirs = dirs;
+    watcher.files = files;
+    watcher.prompt_dirs = prompt_dirs;
+}
+
+/// The file holding the settings in use, which is the active profile unless that's an overlay.
+/// Overlays come along with their path, to be merged on top of `path`.
+fn settings_source(path: &Path) -> Result<(PathBuf, Option<(PathBuf, Value)>), String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let Some(profile) = active_profile(dir) else {
+        return Ok((path.to_path_buf(), None));
+    };
+
+    let profile_path = profile_path(dir, &profile);
+    match as_overlay(read_value(&profile_path)?) {
+        Some(overlay) => Ok((path.to_path_buf(), Some((profile_path, overlay)))),
+        None => Ok((profile_path, None)),
+    }
+}
+
+/// Reads the settings in `path`, upgraded to the current version, along with the version they had
+/// when that's older. They're only deserialized along with what they include, as they don't need
+/// what the included files have. There being no file yet means the default settings.
+fn read_settings(path: &Path) -> Result<(Value, Option<u64>), String> {
+    if !path.exists() {
+        let value = serde_json::to_value(Settings::default()).map_err(|e| e.to_string())?;
+        return Ok((value, None));
+    }
+    let mut value = read_value(path)?;
+    // Taking them to be from before versioning would rewrite them, losing the comments in them
+    let format = Format::of(path);
+    if format != Format::Json && value.get("version").is_none() {
+        let version = match format {
+            Format::Toml => format!("version = {}", CURRENT_VERSION),
+            _ => format!("version: {}", CURRENT_VERSION),
+        };
+        return Err(format!(
+            "{}: Missing `version`, add `{}` when the settings are written for this version of plock",
+            file_name(path), version,
+        ));
+    }
+    let migrated_from = migrate(&mut value)?;
+    Ok((value, migrated_from))
+}
+
+/// Reads a settings file in the format its extension says
+pub(crate) fn read_value(path: &Path) -> Result<Value, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Format::of(path).parse(&data).map_err(|e| format!("{}: {}", file_name(path), e))
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().unwrap_or_default().to_string_lossy().to_string()
+}
+
+/// The profiles next to the settings file, and which of them is in use
+pub fn get_profiles(app_handle: AppHandle) -> Result<(Vec<String>, Option<String>), String> {
+    let path = get_settings_path(app_handle)?;
+    let dir = path.parent().unwrap_or(Path::new("."));
+    Ok((list_profiles(dir), active_profile(dir)))
+}
+
+/// Switches to the profile `name`, or back to settings.json when `None`. Takes effect with the
+/// next `load_settings`.
+pub fn switch_profile(app_handle: AppHandle, name: Option<&str>) -> Result<(), String> {
+    let path = get_settings_path(app_handle)?;
+    set_active_profile(path.parent().unwrap_or(Path::new(".")), name)
+}
+
+/// Writes the JSON Schema of the settings to `path`, unless it's there already
+fn write_schema(path: &Path) -> Result<(), String> {
+    let schema = serde_json::to_string_pretty(&schema_for!(Settings)).map_err(|e| e.to_string())?;
+    if fs::read_to_string(path).is_ok_and(|existing| existing == schema) {
+        return Ok(());
+    }
+    fs::write(path, schema).map_err(|e| e.to_string())
+}
+
+/// Copies the settings file next to itself before it's overwritten with migrated settings
+fn backup_settings(path: &Path, version: u64) -> Result<PathBuf, String> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| e.to_string())?
+        .as_secs();
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let backup = path.with_file_name(format!("{}.v{}.{}.{}", name, version, timestamp, extension));
+    fs::copy(path, &backup)
+        .map_err(|e| format!("Failed to back up settings to {}: {}", backup.display(), e))?;
+    Ok(backup)
+}
+
 pub fn get_settings_path(app_handle: AppHandle) -> Result<PathBuf, String> {
     let path = app_handle.path_resolver().app_local_data_dir().ok_or(
         "Failed to get local data dir".to_string()
     )?;
-    Ok(path.join(Path::new("settings.json")))
+    Ok(find_file(&path, "settings"))
+}
+
+/// Where the app keeps its settings, for when it isn't running
+pub fn default_settings_path(config: &Config) -> Result<PathBuf, String> {
+    let path = app_local_data_dir(config).ok_or("Failed to get local data dir".to_string())?;
+    Ok(find_file(&path, "settings"))
 }
 
 pub fn ensure_local_data_dir(app_handle: AppHandle) -> Result<String, ()> {
@@ -162,37 +547,239 @@ pub fn ensure_local_data_dir(app_handle: AppHandle) -> Result<String, ()> {
     Err(())
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(default)]
 pub struct Ollama {
-    pub model: String,
+    pub host: String,
+    pub port: u16,
+    /// Falls back to the `OLLAMA_MODEL` environment variable when not set
+    pub model: Option<String>,
+    pub system: Option<String>,
+    pub options: OllamaOptions,
+    /// How long the model stays loaded after a request, e.g. "10m", "1h", "0" to unload or "-1" to keep forever
+    pub keep_alive: Option<String>,
+    pub format: Option<OllamaFormat>,
+    pub timeout_secs: Option<u64>,
 }
 
 impl Default for Ollama {
     fn default() -> Self {
         Self {
-            model: "openhermes2.5-mistral".to_string(),
+            host: "http://localhost".to_string(),
+            port: 11434,
+            model: None,
+            system: None,
+            options: OllamaOptions::default(),
+            keep_alive: None,
+            format: None,
+            timeout_secs: None,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(default)]
+pub struct OllamaOptions {
+    pub temperature: Option<f32>,
+    pub num_ctx: Option<u32>,
+    pub top_p: Option<f32>,
+    pub stop: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum OllamaFormat {
+    Json,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(default)]
+pub struct OpenAi {
+    pub base_url: String,
+    pub model: String,
+    /// Name of the environment variable holding the API key, local servers usually don't need one
+    pub api_key_env: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub timeout_secs: Option<u64>,
+}
+
+impl Default for OpenAi {
+    fn default() -> Self {
+        Self {
+            base_url: "https://api.openai.com/v1".to_string(),
+            model: "gpt-3.5-turbo".to_string(),
+            api_key_env: Some("OPENAI_API".to_string()),
+            temperature: None,
+            max_tokens: None,
+            timeout_secs: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Process {
+    pub name: Option<String>,
+    pub process_type: ProcessType,
+}
+
+/// A process is written as its type plus an optional name, e.g. `{"name": "gpt", "command": [...]}`
+impl Serialize for Process {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Named<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            name: &'a Option<String>,
+            #[serde(flatten)]
+            process_type: &'a ProcessType,
+        }
+
+        Named { name: &self.name, process_type: &self.process_type }.serialize(serializer)
+    }
+}
+
+/// The schema of the process type, with `name` allowed alongside every type
+impl JsonSchema for Process {
+    fn schema_name() -> String {
+        "Process".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let name = gen.subschema_for::<String>();
+        let mut schema = SchemaObject::from(ProcessType::json_schema(gen));
+        let variants = schema.subschemas().one_of.iter_mut().flatten();
+        for variant in variants {
+            if let Schema::Object(variant) = variant {
+                variant.object().properties.insert("name".to_string(), name.clone());
+            }
+        }
+        Schema::Object(schema)
+    }
+}
+
+impl<'de> Deserialize<'de> for Process {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut value = Value::deserialize(deserializer)?;
+        let name = match value.as_object_mut().and_then(|map| map.remove("name")) {
+            Some(Value::String(name)) => Some(name),
+            Some(_) => return Err(de::Error::custom("process name must be a string")),
+            None => None,
+        };
+        let process_type = serde_json::from_value(value).map_err(de::Error::custom)?;
+        Ok(Process { name, process_type })
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum ProcessType {
-    Ollama,
-    Command(Vec<String>),
+    Ollama(Ollama),
+    Command(CommandProcess),
+    #[serde(rename = "openai")]
+    OpenAi(OpenAi),
+    Backend(CustomBackend),
+}
+
+impl ProcessType {
+    /// The name of the backend that runs this process, and the options it is configured with
+    pub fn backend(&self) -> (String, Value) {
+        let (name, options) = match self {
+            ProcessType::Ollama(config) => ("ollama", serde_json::to_value(config)),
+            ProcessType::Command(config) => ("command", serde_json::to_value(config)),
+            ProcessType::OpenAi(config) => ("openai", serde_json::to_value(config)),
+            ProcessType::Backend(config) => return (config.name.clone(), config.options.clone()),
+        };
+        (name.to_string(), options.expect("Process options are always valid json"))
+    }
+
+    /// The variables of the run, besides the constants, the process gets
+    pub fn export(&self) -> &[String] {
+        match self {
+            ProcessType::Command(config) => &config.export,
+            ProcessType::Ollama(_) | ProcessType::OpenAi(_) | ProcessType::Backend(_) => &[],
+        }
+    }
+
+    /// How long the process may run before it is stopped
+    pub fn timeout(&self) -> Option<Duration> {
+        let timeout_secs = match self {
+            ProcessType::Ollama(config) => config.timeout_secs,
+            ProcessType::Command(config) => config.timeout_secs,
+            ProcessType::OpenAi(config) => config.timeout_secs,
+            ProcessType::Backend(config) => config.timeout_secs,
+        };
+        timeout_secs.map(Duration::from_secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+pub struct CommandProcess {
+    /// The program and its arguments, the prompt is passed as the last argument. When empty, the
+    /// prompt itself is run as a shell script.
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub protocol: CommandProtocol,
+    /// Makes whatever the command wrote to stderr available as `$STDERR`
+    #[serde(default)]
+    pub expose_stderr: bool,
+    #[serde(default)]
+    pub input_mode: InputMode,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Variables of the run the command gets as environment variables, e.g. `["SELECTION"]`. The
+    /// `environment` constants are always passed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub export: Vec<String>,
+}
+
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandProtocol {
+    /// Everything the command writes is the output
+    #[default]
+    Text,
+    /// Every line is a json event: text, image, set_var, progress, error or done
+    Ndjson,
+}
+
+/// How the prompt is handed to a command
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum InputMode {
+    /// As the last argument
+    #[default]
+    Argv,
+    /// Written to the command's stdin, best for large prompts
+    Stdin,
+    /// Written to a temporary file whose path is the last argument
+    File,
+    /// As the `PLOCK_PROMPT` environment variable
+    Env,
+}
+
+/// A process handled by a backend registered by name, e.g. `{ "name": "mock" }`
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct CustomBackend {
+    pub name: String,
+    #[serde(default)]
+    pub options: Value,
+    pub timeout_secs: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum Step {
     StreamTextToScreen,
     WriteFinalTextToScreen,
     WriteImageToScreen,
     StoreAsEnvVar(String),
-    Trigger(usize),
+    Trigger(Reference),
+    ResetConversation(String),
+    ForkConversation { from: String, into: String },
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "snake_case")]
 pub enum SelectionAction {
     Remove,
@@ -200,30 +787,193 @@ pub enum SelectionAction {
     Nothing,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(default)]
+pub struct HistorySettings {
+    /// Whether runs are recorded at all, they include what was selected and the clipboard
+    pub enabled: bool,
+    /// The most runs kept, the oldest are dropped first, none at all when 0
+    pub max_entries: usize,
+    /// Runs older than this are dropped, they're kept however old they are when not set
+    pub max_age_days: Option<u64>,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_entries: 1000,
+            max_age_days: None,
+        }
+    }
+}
+
+/// What happens when a trigger is invoked while it's still running
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Overlap {
+    /// Starts once the runs before it are done
+    #[default]
+    Queue,
+    /// Doesn't start
+    Reject,
+    /// Cancels the runs before it and starts once they stopped
+    CancelPrevious,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 pub struct Trigger {
+    /// Lets `trigger` steps point at this trigger by name
+    #[serde(default)]
+    pub name: Option<String>,
     pub trigger_with_shortcut: Option<String>,
-    pub process: usize,
-    pub prompt: usize,
+    /// Falls back to the default process of the prompt when not set
+    pub process: Option<Reference>,
+    pub prompt: Reference,
     pub next_steps: Vec<Step>,
     pub selection_action: Option<SelectionAction>,
+    /// Name of the conversation this trigger continues, every run is independent when not set
+    pub conversation: Option<String>,
+    /// Stops the run after this long, on top of any timeout of the process
+    pub timeout_secs: Option<u64>,
+    /// Runs of the trigger are queued when not set
+    pub overlap: Option<Overlap>,
 }
 
 impl Default for Trigger {
     fn default() -> Self {
         Self {
+            name: None,
             trigger_with_shortcut: None,
-            process: 0,
-            prompt: 0,
+            process: Some(Reference::Index(0)),
+            prompt: Reference::Index(0),
             next_steps: vec![StreamTextToScreen],
             selection_action: None,
+            conversation: None,
+            timeout_secs: None,
+            overlap: None,
+        }
+    }
+}
+
+impl Trigger {
+    /// How messages refer to the trigger at `index`
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("\"{}\"", name),
+            None => format!("#{}", index),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
 pub struct CustomPrompt {
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub prompt: String,
+    /// A Markdown or text file holding the prompt instead, see `prompts`
+    pub file: Option<PathBuf>,
+    pub description: Option<String>,
+    /// The process of triggers that don't set one
+    pub process: Option<Reference>,
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn includes_keep_what_the_including_file_leaves_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = json!({
+            "version": 2,
+            "environment": {},
+            "processes": [{"name": "mock", "backend": {"name": "mock", "options": {}}}],
+            "prompts": [{"name": "shared", "prompt": "${SELECTION}"}],
+            "triggers": [],
+            "history": {"enabled": false},
+        });
+        fs::write(dir.path().join("shared.json"), shared.to_string()).unwrap();
+        let settings = json!({
+            "version": 2,
+            "include": ["shared.json"],
+            "environment": {},
+            "processes": [],
+            "prompts": [],
+            "triggers": [{"process": "mock", "prompt": "shared", "next_steps": ["stream_text_to_screen"]}],
+        });
+        let path = dir.path().join("settings.json");
+        fs::write(&path, settings.to_string()).unwrap();
+
+        let (settings, _) = load_settings_file(&path).unwrap();
+
+        assert!(!settings.history.enabled);
+        assert_eq!(settings.processes.len(), 1);
+        assert_eq!(settings.triggers.len(), 1);
+    }
+
+    #[test]
+    fn including_files_only_need_what_they_add_also_after_writing_them_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = json!({
+            "version": 2,
+            "environment": {"MODEL": "shared"},
+            "processes": [{"name": "mock", "backend": {"name": "mock", "options": {}}}],
+            "prompts": [{"name": "shared", "prompt": "${SELECTION}"}],
+            "triggers": [],
+            "history": {"enabled": false},
+        });
+        fs::write(dir.path().join("shared.json"), shared.to_string()).unwrap();
+        let settings = json!({
+            "version": 2,
+            "include": ["shared.json"],
+            "triggers": [{"process": "mock", "prompt": "shared", "next_steps": ["stream_text_to_screen"]}],
+        });
+        let path = dir.path().join("settings.json");
+        fs::write(&path, settings.to_string()).unwrap();
+
+        let Loaded { stored, source, migrated_from, .. } = read_all(&path, &mut Vec::new()).unwrap();
+        write_back(&path, &source, stored, migrated_from).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        let (settings, _) = load_settings_file(&path).unwrap();
+
+        assert_eq!(written["$schema"], SCHEMA_FILE);
+        assert!(written.get("environment").is_none());
+        assert!(written.get("history").is_none());
+        assert_eq!(settings.environment["MODEL"], "shared");
+        assert!(!settings.history.enabled);
+        assert_eq!(settings.processes.len(), 1);
+        assert_eq!(settings.triggers.len(), 1);
+    }
+
+    #[test]
+    fn writes_back_new_fields_of_files_without_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.json");
+        let settings = json!({"version": 2, "environment": {}, "processes": [], "prompts": [], "triggers": []});
+        fs::write(&path, settings.to_string()).unwrap();
+
+        let Loaded { stored, source, migrated_from, .. } = read_all(&path, &mut Vec::new()).unwrap();
+        write_back(&path, &source, stored, migrated_from).unwrap();
+        let written: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+
+        assert_eq!(written["$schema"], SCHEMA_FILE);
+        assert!(written.get("history").is_some());
+    }
+
+    #[test]
+    fn refuses_toml_without_a_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.toml");
+        let settings = "# Mine\nenvironment = {}\nprocesses = []\nprompts = []\ntriggers = []\n";
+        fs::write(&path, settings).unwrap();
+
+        let error = load_settings_file(&path).err().unwrap();
+
+        assert!(error.contains("version = 2"), "{}", error);
+        assert_eq!(fs::read_to_string(&path).unwrap(), settings);
+    }
+}
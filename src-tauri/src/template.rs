@@ -0,0 +1,420 @@
+//! Renders `CustomPrompt.prompt`s.
+//!
+//! - `${VAR}` is replaced with the value of `VAR`, and fails the render when it isn't set
+//! - `${VAR:-fallback}` uses `fallback` when `VAR` isn't set, `\|` and `\}` write a `|` or `}` in it
+//! - `${VAR|trim|truncate:200}` pipes the value through filters: `trim`, `upper`, `lower`,
+//!   `json_escape`, `shell_escape` and `truncate:<chars>`
+//! - `${if VAR}...${else}...${end}` keeps the first part when `VAR` is set and not empty, `!VAR`
+//!   negates the condition
+//! - `$VAR` is the older form, it is only replaced when `VAR` is set so `$5` or `$HOME` in a
+//!   script stay as they are
+//! - `$${` is a literal `${`
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum TemplateError {
+    UnknownVariable(String),
+    UnknownFilter(String),
+    InvalidFilterArgument { filter: String, argument: String },
+    Unclosed { offset: usize },
+    UnexpectedElse { offset: usize },
+    UnexpectedEnd { offset: usize },
+    MissingEnd(String),
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::UnknownVariable(name) => write!(
+                f, "Unknown variable ${{{}}}, set it or give it a default like ${{{}:-}}", name, name,
+            ),
+            TemplateError::UnknownFilter(filter) => write!(f, "Unknown filter `{}`", filter),
+            TemplateError::InvalidFilterArgument { filter, argument } => {
+                write!(f, "Invalid argument `{}` for filter `{}`", argument, filter)
+            }
+            TemplateError::Unclosed { offset } => write!(f, "Missing `}}` for `${{` at {}", offset),
+            TemplateError::UnexpectedElse { offset } => {
+                write!(f, "`${{else}}` at {} is not inside an `${{if}}`", offset)
+            }
+            TemplateError::UnexpectedEnd { offset } => {
+                write!(f, "`${{end}}` at {} is not closing an `${{if}}`", offset)
+            }
+            TemplateError::MissingEnd(condition) => {
+                write!(f, "`${{if {}}}` is missing its `${{end}}`", condition)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+enum Node {
+    Text(String),
+    Variable {
+        name: String,
+        default: Option<String>,
+        filters: Vec<String>,
+    },
+    Legacy(String),
+    If {
+        name: String,
+        negate: bool,
+        then: Vec<Node>,
+        otherwise: Vec<Node>,
+    },
+}
+
+enum Token {
+    Text(String),
+    Expression { content: String, offset: usize },
+    Legacy(String),
+}
+
+/// Renders `template`, looking up every variable it uses with `lookup`. `Ok(None)` means the
+/// variable isn't set, while an error from `lookup` stops the render and is returned as is.
+pub fn render<E: From<TemplateError>>(
+    template: &str,
+    lookup: impl Fn(&str) -> Result<Option<String>, E>,
+) -> Result<String, E> {
+    let nodes = parse(tokenize(template)?)?;
+    let mut out = String::new();
+    evaluate(&nodes, &lookup, &mut out)?;
+    Ok(out)
+}
+
+/// Checks `template` without rendering it, returning the variables it can't be rendered without:
+/// those used as `${VAR}` without a fallback.
+pub fn required_variables(template: &str) -> Result<Vec<String>, TemplateError> {
+    fn collect(nodes: &[Node], required: &mut Vec<String>) -> Result<(), TemplateError> {
+        for node in nodes {
+            match node {
+                Node::Variable { name, default, filters } => {
+                    for filter in filters {
+                        apply_filter(filter, String::new())?;
+                    }
+                    if default.is_none() && !required.contains(name) {
+                        required.push(name.clone());
+                    }
+                }
+                Node::If { then, otherwise, .. } => {
+                    collect(then, required)?;
+                    collect(otherwise, required)?;
+                }
+                Node::Text(_) | Node::Legacy(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    let mut required = Vec::new();
+    collect(&parse(tokenize(template)?)?, &mut required)?;
+    Ok(required)
+}
+
+fn tokenize(template: &str) -> Result<Vec<Token>, TemplateError> {
+    let mut tokens = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+
+    while let Some(dollar) = rest.find('$') {
+        text.push_str(&rest[..dollar]);
+        let offset = template.len() - rest.len() + dollar;
+        let after = &rest[dollar + 1..];
+
+        if let Some(after) = after.strip_prefix("${") {
+            text.push_str("${");
+            rest = after;
+        } else if let Some(after) = after.strip_prefix('{') {
+            let close = find_unescaped(after, '}').ok_or(TemplateError::Unclosed { offset })?;
+            if !text.is_empty() {
+                tokens.push(Token::Text(std::mem::take(&mut text)));
+            }
+            tokens.push(Token::Expression { content: after[..close].trim().to_string(), offset });
+            rest = &after[close + 1..];
+        } else {
+            let name_len = after
+                .char_indices()
+                .take_while(|(i, c)| c.is_ascii_alphabetic() || *c == '_' || (*i > 0 && c.is_ascii_digit()))
+                .count();
+            if name_len == 0 {
+                text.push('$');
+            } else {
+                if !text.is_empty() {
+                    tokens.push(Token::Text(std::mem::take(&mut text)));
+                }
+                tokens.push(Token::Legacy(after[..name_len].to_string()));
+            }
+            rest = &after[name_len..];
+        }
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        tokens.push(Token::Text(text));
+    }
+
+    Ok(tokens)
+}
+
+fn parse(tokens: Vec<Token>) -> Result<Vec<Node>, TemplateError> {
+    // An `${if}` that hasn't seen its `${end}` yet, nodes are collected into `current` meanwhile
+    struct OpenIf {
+        name: String,
+        negate: bool,
+        enclosing: Vec<Node>,
+        then: Option<Vec<Node>>,
+    }
+
+    let mut stack: Vec<OpenIf> = Vec::new();
+    let mut current = Vec::new();
+
+    for token in tokens {
+        let (content, offset) = match token {
+            Token::Text(text) => {
+                current.push(Node::Text(text));
+                continue;
+            }
+            Token::Legacy(name) => {
+                current.push(Node::Legacy(name));
+                continue;
+            }
+            Token::Expression { content, offset } => (content, offset),
+        };
+
+        if let Some(condition) = content.strip_prefix("if ") {
+            let condition = condition.trim();
+            let (name, negate) = match condition.strip_prefix('!') {
+                Some(name) => (name.trim().to_string(), true),
+                None => (condition.to_string(), false),
+            };
+            let enclosing = std::mem::take(&mut current);
+            stack.push(OpenIf { name, negate, enclosing, then: None });
+        } else if content == "else" {
+            let open = stack
+                .last_mut()
+                .filter(|open| open.then.is_none())
+                .ok_or(TemplateError::UnexpectedElse { offset })?;
+            open.then = Some(std::mem::take(&mut current));
+        } else if content == "end" {
+            let open = stack.pop().ok_or(TemplateError::UnexpectedEnd { offset })?;
+            let branch = std::mem::replace(&mut current, open.enclosing);
+            let (then, otherwise) = match open.then {
+                Some(then) => (then, branch),
+                None => (branch, Vec::new()),
+            };
+            current.push(Node::If { name: open.name, negate: open.negate, then, otherwise });
+        } else {
+            current.push(parse_variable(&content));
+        }
+    }
+
+    if let Some(open) = stack.pop() {
+        let condition = if open.negate { format!("!{}", open.name) } else { open.name };
+        return Err(TemplateError::MissingEnd(condition));
+    }
+
+    Ok(current)
+}
+
+fn parse_variable(content: &str) -> Node {
+    let (variable, filters) = match find_unescaped(content, '|') {
+        Some(bar) => (&content[..bar], content[bar + 1..].split('|').map(|filter| filter.trim().to_string()).collect()),
+        None => (content, Vec::new()),
+    };
+
+    let (name, default) = match variable.split_once(":-") {
+        Some((name, default)) => (name.trim(), Some(default.replace("\\|", "|").replace("\\}", "}"))),
+        None => (variable.trim(), None),
+    };
+
+    Node::Variable { name: name.to_string(), default, filters }
+}
+
+/// Where the first `c` in `text` that doesn't follow a `\` is
+fn find_unescaped(text: &str, c: char) -> Option<usize> {
+    let mut escaped = false;
+    for (i, found) in text.char_indices() {
+        if found == c && !escaped {
+            return Some(i);
+        }
+        escaped = found == '\\' && !escaped;
+    }
+    None
+}
+
+fn evaluate<E: From<TemplateError>>(
+    nodes: &[Node],
+    lookup: &impl Fn(&str) -> Result<Option<String>, E>,
+    out: &mut String,
+) -> Result<(), E> {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Legacy(name) => match lookup(name)? {
+                Some(value) => out.push_str(&value),
+                None => {
+                    out.push('$');
+                    out.push_str(name);
+                }
+            },
+            Node::Variable { name, default, filters } => {
+                let mut value = lookup(name)?
+                    .or_else(|| default.clone())
+                    .ok_or_else(|| TemplateError::UnknownVariable(name.clone()))?;
+                for filter in filters {
+                    value = apply_filter(filter, value)?;
+                }
+                out.push_str(&value);
+            }
+            Node::If { name, negate, then, otherwise } => {
+                let is_set = lookup(name)?.is_some_and(|value| !value.is_empty());
+                if is_set != *negate {
+                    evaluate(then, lookup, out)?;
+                } else {
+                    evaluate(otherwise, lookup, out)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn apply_filter(filter: &str, value: String) -> Result<String, TemplateError> {
+    let (name, argument) = match filter.split_once(':') {
+        Some((name, argument)) => (name.trim(), Some(argument.trim())),
+        None => (filter, None),
+    };
+
+    Ok(match name {
+        "trim" => value.trim().to_string(),
+        "upper" => value.to_uppercase(),
+        "lower" => value.to_lowercase(),
+        "json_escape" => {
+            let quoted = serde_json::to_string(&value).expect("Strings are always valid json");
+            quoted[1..quoted.len() - 1].to_string()
+        }
+        "shell_escape" => format!("'{}'", value.replace('\'', "'\\''")),
+        "truncate" => {
+            let argument = argument.unwrap_or_default();
+            let chars = argument.parse::<usize>().map_err(|_| TemplateError::InvalidFilterArgument {
+                filter: name.to_string(),
+                argument: argument.to_string(),
+            })?;
+            value.chars().take(chars).collect()
+        }
+        _ => return Err(TemplateError::UnknownFilter(filter.to_string())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_with(template: &str, variables: &[(&str, &str)]) -> Result<String, TemplateError> {
+        render(template, |name| {
+            Ok(variables.iter().find(|(key, _)| *key == name).map(|(_, value)| value.to_string()))
+        })
+    }
+
+    #[test]
+    fn replaces_variables() {
+        assert_eq!(render_with("Fix ${SELECTION}.", &[("SELECTION", "this")]).unwrap(), "Fix this.");
+        assert_eq!(render_with("${ A }", &[("A", "spaced")]).unwrap(), "spaced");
+    }
+
+    #[test]
+    fn fails_on_unknown_variables() {
+        let error = render_with("${MISSING}", &[]).unwrap_err();
+        assert!(matches!(error, TemplateError::UnknownVariable(name) if name == "MISSING"));
+    }
+
+    #[test]
+    fn uses_the_default_when_not_set() {
+        assert_eq!(render_with("${A:-none}", &[]).unwrap(), "none");
+        assert_eq!(render_with("${A:-none}", &[("A", "set")]).unwrap(), "set");
+        assert_eq!(render_with("${A:-}", &[]).unwrap(), "");
+    }
+
+    #[test]
+    fn escapes_bars_and_braces_in_defaults() {
+        assert_eq!(render_with("${A:-a\\|b}", &[]).unwrap(), "a|b");
+        assert_eq!(render_with("${A:-{x\\}}", &[]).unwrap(), "{x}");
+        assert_eq!(render_with("${A:- a\\|b |upper|trim}", &[]).unwrap(), "A|B");
+    }
+
+    #[test]
+    fn applies_filters_in_order() {
+        let variables = [("A", "  Hello World  ")];
+        assert_eq!(render_with("${A|trim|upper}", &variables).unwrap(), "HELLO WORLD");
+        assert_eq!(render_with("${A|trim|lower|truncate:5}", &variables).unwrap(), "hello");
+        assert_eq!(render_with("${A|json_escape}", &[("A", "\"a\"\n")]).unwrap(), "\\\"a\\\"\\n");
+        assert_eq!(render_with("${A|shell_escape}", &[("A", "it's")]).unwrap(), "'it'\\''s'");
+    }
+
+    #[test]
+    fn fails_on_unknown_filters_and_bad_arguments() {
+        let error = render_with("${A|reverse}", &[("A", "a")]).unwrap_err();
+        assert!(matches!(error, TemplateError::UnknownFilter(filter) if filter == "reverse"));
+
+        let error = render_with("${A|truncate:many}", &[("A", "a")]).unwrap_err();
+        assert!(matches!(
+            error,
+            TemplateError::InvalidFilterArgument { filter, argument } if filter == "truncate" && argument == "many"
+        ));
+    }
+
+    #[test]
+    fn keeps_the_branch_the_condition_picks() {
+        let template = "${if A}a is ${A}${else}no a${end}";
+        assert_eq!(render_with(template, &[("A", "set")]).unwrap(), "a is set");
+        assert_eq!(render_with(template, &[("A", "")]).unwrap(), "no a");
+        assert_eq!(render_with(template, &[]).unwrap(), "no a");
+        assert_eq!(render_with("${if !A}no a${end}", &[]).unwrap(), "no a");
+    }
+
+    #[test]
+    fn nests_conditions() {
+        let template = "${if A}${if B}both${else}only a${end}${else}none${end}";
+        assert_eq!(render_with(template, &[("A", "1"), ("B", "1")]).unwrap(), "both");
+        assert_eq!(render_with(template, &[("A", "1")]).unwrap(), "only a");
+        assert_eq!(render_with(template, &[("B", "1")]).unwrap(), "none");
+    }
+
+    #[test]
+    fn fails_on_unbalanced_conditions() {
+        assert!(matches!(render_with("${if A}a", &[]).unwrap_err(), TemplateError::MissingEnd(condition) if condition == "A"));
+        assert!(matches!(render_with("${if !A}a", &[]).unwrap_err(), TemplateError::MissingEnd(condition) if condition == "!A"));
+        assert!(matches!(render_with("a${else}", &[]).unwrap_err(), TemplateError::UnexpectedElse { offset: 1 }));
+        assert!(matches!(
+            render_with("${if A}${else}${else}${end}", &[]).unwrap_err(),
+            TemplateError::UnexpectedElse { offset: 14 }
+        ));
+        assert!(matches!(render_with("a${end}", &[]).unwrap_err(), TemplateError::UnexpectedEnd { offset: 1 }));
+    }
+
+    #[test]
+    fn fails_on_unclosed_expressions() {
+        assert!(matches!(render_with("a ${A", &[]).unwrap_err(), TemplateError::Unclosed { offset: 2 }));
+        assert!(matches!(render_with("${A:-\\}", &[]).unwrap_err(), TemplateError::Unclosed { offset: 0 }));
+    }
+
+    #[test]
+    fn escapes_dollar_braces() {
+        assert_eq!(render_with("$${A} $${", &[("A", "a")]).unwrap(), "${A} ${");
+    }
+
+    #[test]
+    fn only_replaces_legacy_variables_that_are_set() {
+        let variables = [("SELECTION", "this")];
+        assert_eq!(render_with("Fix $SELECTION", &variables).unwrap(), "Fix this");
+        assert_eq!(render_with("echo $HOME $5 $ $_x1", &variables).unwrap(), "echo $HOME $5 $ $_x1");
+        assert_eq!(render_with("$SELECTION_2", &variables).unwrap(), "$SELECTION_2");
+    }
+
+    #[test]
+    fn lists_the_variables_without_a_default() {
+        let required = required_variables("${A} ${B:-b} ${if C}${D|trim}${end} $E ${A}").unwrap();
+        assert_eq!(required, vec!["A".to_string(), "D".to_string()]);
+        assert!(matches!(required_variables("${A|nope}"), Err(TemplateError::UnknownFilter(_))));
+    }
+}
@@ -0,0 +1,339 @@
+//! Checks settings before they replace the ones in use, so a typo can't break every trigger.
+
+use crate::backends::BACKENDS;
+use crate::settings::{ProcessType, Reference, Settings, Step};
+use crate::template;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::path::Path;
+
+/// Variables every run has
+const BUILTINS: [&str; 3] = ["SELECTION", "CLIPBOARD", "OCR"];
+
+/// What `CmdOrCtrl` stands for on this platform, so it clashes with shortcuts spelling it out
+const COMMAND_OR_CONTROL: &str = if cfg!(target_os = "macos") { "COMMAND" } else { "CONTROL" };
+
+const MODIFIERS: [(&str, &str); 12] = [
+    ("COMMAND", "COMMAND"), ("CMD", "COMMAND"), ("SUPER", "COMMAND"),
+    ("CONTROL", "CONTROL"), ("CTRL", "CONTROL"),
+    ("ALT", "ALT"), ("OPTION", "ALT"),
+    ("SHIFT", "SHIFT"),
+    ("COMMANDORCONTROL", COMMAND_OR_CONTROL), ("COMMANDORCTRL", COMMAND_OR_CONTROL),
+    ("CMDORCTRL", COMMAND_OR_CONTROL), ("CMDORCONTROL", COMMAND_OR_CONTROL),
+];
+
+/// The keys Tauri's accelerators know by name, besides letters, digits, `F1` to `F24` and `NUMPAD0` to
+/// `NUMPAD9`, which `normalize_shortcut` handles itself
+const KEYS: &[(&str, &str)] = &[
+    ("`", "BACKQUOTE"), ("BACKQUOTE", "BACKQUOTE"), ("\\", "BACKSLASH"), ("BACKSLASH", "BACKSLASH"),
+    ("[", "BRACKETLEFT"), ("BRACKETLEFT", "BRACKETLEFT"), ("]", "BRACKETRIGHT"), ("BRACKETRIGHT", "BRACKETRIGHT"),
+    (",", "COMMA"), ("COMMA", "COMMA"), ("=", "EQUAL"), ("EQUAL", "EQUAL"), ("-", "MINUS"), ("MINUS", "MINUS"),
+    (".", "PERIOD"), ("PERIOD", "PERIOD"), ("'", "QUOTE"), ("QUOTE", "QUOTE"),
+    (";", "SEMICOLON"), ("SEMICOLON", "SEMICOLON"), ("/", "SLASH"), ("SLASH", "SLASH"), ("PLUS", "PLUS"),
+    ("BACKSPACE", "BACKSPACE"), ("CAPSLOCK", "CAPSLOCK"), ("ENTER", "ENTER"), ("SPACE", "SPACE"), ("TAB", "TAB"),
+    ("DELETE", "DELETE"), ("END", "END"), ("HOME", "HOME"), ("INSERT", "INSERT"),
+    ("PAGEDOWN", "PAGEDOWN"), ("PAGEUP", "PAGEUP"), ("PRINTSCREEN", "PRINTSCREEN"), ("SCROLLLOCK", "SCROLLLOCK"),
+    ("UP", "ARROWUP"), ("ARROWUP", "ARROWUP"), ("DOWN", "ARROWDOWN"), ("ARROWDOWN", "ARROWDOWN"),
+    ("LEFT", "ARROWLEFT"), ("ARROWLEFT", "ARROWLEFT"), ("RIGHT", "ARROWRIGHT"), ("ARROWRIGHT", "ARROWRIGHT"),
+    ("ESC", "ESCAPE"), ("ESCAPE", "ESCAPE"), ("NUMLOCK", "NUMLOCK"),
+    ("NUMADD", "NUMPADADD"), ("NUMPADADD", "NUMPADADD"), ("NUMDECIMAL", "NUMPADDECIMAL"),
+    ("NUMPADDECIMAL", "NUMPADDECIMAL"), ("NUMDIVIDE", "NUMPADDIVIDE"), ("NUMPADDIVIDE", "NUMPADDIVIDE"),
+    ("NUMENTER", "NUMPADENTER"), ("NUMPADENTER", "NUMPADENTER"), ("NUMEQUAL", "NUMPADEQUAL"),
+    ("NUMPADEQUAL", "NUMPADEQUAL"), ("NUMMULTIPLY", "NUMPADMULTIPLY"), ("NUMPADMULTIPLY", "NUMPADMULTIPLY"),
+    ("NUMSUBTRACT", "NUMPADSUBTRACT"), ("NUMPADSUBTRACT", "NUMPADSUBTRACT"),
+    ("VOLUMEDOWN", "AUDIOVOLUMEDOWN"), ("AUDIOVOLUMEDOWN", "AUDIOVOLUMEDOWN"),
+    ("VOLUMEUP", "AUDIOVOLUMEUP"), ("AUDIOVOLUMEUP", "AUDIOVOLUMEUP"),
+    ("VOLUMEMUTE", "AUDIOVOLUMEMUTE"), ("AUDIOVOLUMEMUTE", "AUDIOVOLUMEMUTE"),
+    ("MEDIAPLAY", "MEDIAPLAY"), ("MEDIAPAUSE", "MEDIAPAUSE"), ("MEDIAPLAYPAUSE", "MEDIAPLAYPAUSE"),
+    ("MEDIASTOP", "MEDIASTOP"), ("MEDIATRACKNEXT", "MEDIATRACKNEXT"), ("MEDIATRACKPREV", "MEDIATRACKPREVIOUS"),
+    ("MEDIATRACKPREVIOUS", "MEDIATRACKPREVIOUS"),
+];
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The settings can't be used
+    Error,
+    /// The settings are used, but something probably won't work
+    Warning,
+}
+
+#[derive(Serialize)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Where in the settings, e.g. `triggers[2].prompt`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn error(&mut self, path: String, message: String) {
+        self.0.push(Problem { severity: Severity::Error, path, message });
+    }
+
+    fn warning(&mut self, path: String, message: String) {
+        self.0.push(Problem { severity: Severity::Warning, path, message });
+    }
+}
+
+/// Every problem found in `settings`, an empty list means they're good to use
+pub fn validate(settings: &Settings) -> Vec<Problem> {
+    let mut problems = Problems::default();
+    check_names(settings, &mut problems);
+    check_processes(settings, &mut problems);
+    check_prompts(settings, &mut problems);
+    check_triggers(settings, &mut problems);
+    problems.0
+}
+
+fn check_names(settings: &Settings, problems: &mut Problems) {
+    let processes = settings.processes.iter().map(|process| process.name.as_deref());
+    let prompts = settings.prompts.iter().map(|prompt| Some(prompt.name.as_str()));
+    let triggers = settings.triggers.iter().map(|trigger| trigger.name.as_deref());
+
+    for (list, names) in [
+        ("processes", processes.collect::<Vec<_>>()),
+        ("prompts", prompts.collect()),
+        ("triggers", triggers.collect()),
+    ] {
+        let mut seen = HashSet::new();
+        for (i, name) in names.into_iter().enumerate() {
+            if let Some(name) = name.filter(|name| !seen.insert(*name)) {
+                problems.warning(
+                    format!("{}[{}].name", list, i),
+                    format!("\"{}\" is used more than once, references use the first one", name),
+                );
+            }
+        }
+    }
+}
+
+fn check_processes(settings: &Settings, problems: &mut Problems) {
+    for (i, process) in settings.processes.iter().enumerate() {
+        match &process.process_type {
+            ProcessType::Command(command) => {
+                if let Some(program) = command.command.first() {
+                    if !binary_exists(program) {
+                        problems.warning(
+                            format!("processes[{}].command.command[0]", i),
+                            format!("`{}` wasn't found", program),
+                        );
+                    }
+                }
+            }
+            ProcessType::Backend(backend) => {
+                if BACKENDS.read().unwrap().get(&backend.name).is_none() {
+                    problems.error(
+                        format!("processes[{}].backend.name", i),
+                        format!("There is no backend named \"{}\"", backend.name),
+                    );
+                }
+            }
+            ProcessType::Ollama(_) | ProcessType::OpenAi(_) => {}
+        }
+    }
+}
+
+fn check_prompts(settings: &Settings, problems: &mut Problems) {
+    let mut known = BUILTINS.iter().map(|name| name.to_string()).collect::<HashSet<String>>();
+    known.extend(settings.environment.keys().cloned());
+    for trigger in settings.triggers.iter() {
+        for step in trigger.next_steps.iter() {
+            if let Step::StoreAsEnvVar(name) = step {
+                known.insert(name.clone());
+            }
+        }
+    }
+    let exposes_stderr = settings.processes.iter().any(|process| {
+        matches!(&process.process_type, ProcessType::Command(command) if command.expose_stderr)
+    });
+    if exposes_stderr {
+        known.insert("STDERR".to_string());
+    }
+
+    for (i, prompt) in settings.prompts.iter().enumerate() {
+        if let Some(process) = &prompt.process {
+            check_process_reference(settings, process, format!("prompts[{}].process", i), problems);
+        }
+
+        let path = format!("prompts[{}].prompt", i);
+        match template::required_variables(&prompt.prompt) {
+            Ok(required) => {
+                for name in required {
+                    // Commands using the ndjson protocol can set any variable, so this might still work
+                    if !known.contains(&name) && !name.starts_with("env.") {
+                        problems.warning(path.clone(), format!("${{{}}} is never set", name));
+                    }
+                }
+            }
+            Err(e) => problems.error(path, e.to_string()),
+        }
+    }
+}
+
+fn check_triggers(settings: &Settings, problems: &mut Problems) {
+    let mut shortcuts = HashMap::new();
+
+    for (i, trigger) in settings.triggers.iter().enumerate() {
+        if let Some(process) = &trigger.process {
+            check_process_reference(settings, process, format!("triggers[{}].process", i), problems);
+        }
+
+        let prompt = trigger.prompt.resolve(&settings.prompts, |prompt| Some(prompt.name.as_str()));
+        match prompt {
+            None => problems.error(
+                format!("triggers[{}].prompt", i),
+                format!("There is no prompt {}", trigger.prompt),
+            ),
+            Some(prompt) if trigger.process.is_none() && settings.prompts[prompt].process.is_none() => {
+                problems.error(
+                    format!("triggers[{}].process", i),
+                    format!("Missing, and prompt {} has no default process", trigger.prompt),
+                );
+            }
+            Some(_) => {}
+        }
+
+        for (j, step) in trigger.next_steps.iter().enumerate() {
+            if let Step::Trigger(next) = step {
+                if settings.trigger_index(next).is_none() {
+                    problems.error(
+                        format!("triggers[{}].next_steps[{}].trigger", i, j),
+                        format!("There is no trigger {}", next),
+                    );
+                }
+            }
+        }
+
+        if let Some(shortcut) = &trigger.trigger_with_shortcut {
+            let path = format!("triggers[{}].trigger_with_shortcut", i);
+            match normalize_shortcut(shortcut) {
+                Ok(normalized) => {
+                    if let Some(first) = shortcuts.insert(normalized, i) {
+                        problems.error(path, format!("`{}` is also the shortcut of trigger #{}", shortcut, first));
+                    }
+                }
+                // Tauri might still know it, registering it tells for sure
+                Err(e) => problems.warning(path, e),
+            }
+        }
+    }
+}
+
+fn check_process_reference(settings: &Settings, process: &Reference, path: String, problems: &mut Problems) {
+    if process.resolve(&settings.processes, |process| process.name.as_deref()).is_none() {
+        problems.error(path, format!("There is no process {}", process));
+    }
+}
+
+/// Checks a shortcut like `Command+Shift+.`, returning it in a form where the same shortcut
+/// written differently compares equal, or what it doesn't know about it
+fn normalize_shortcut(shortcut: &str) -> Result<String, String> {
+    let parts = shortcut.split('+').map(|part| part.trim().to_uppercase()).collect::<Vec<_>>();
+    let (key, modifiers) = parts.split_last().expect("split always yields a part");
+
+    let mut normalized = modifiers
+        .iter()
+        .map(|modifier| {
+            MODIFIERS
+                .iter()
+                .find(|(alias, _)| alias == modifier)
+                .map(|(_, modifier)| modifier.to_string())
+                .ok_or(format!("`{}` in `{}` is not a known modifier", modifier, shortcut))
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+    normalized.sort();
+    normalized.dedup();
+
+    let numbered = |prefix: &str, range: std::ops::RangeInclusive<u8>| {
+        key.strip_prefix(prefix)
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|n| range.contains(n))
+    };
+    let single = |key: &str, kind: fn(&char) -> bool| key.len() == 1 && key.chars().all(|c| kind(&c));
+    // `A` is `KEYA`, `1` is `DIGIT1`
+    let key = if let Some(letter) = key.strip_prefix("KEY").filter(|key| single(key, char::is_ascii_uppercase)) {
+        letter.to_string()
+    } else if let Some(digit) = key.strip_prefix("DIGIT").filter(|key| single(key, char::is_ascii_digit)) {
+        digit.to_string()
+    } else if single(key, char::is_ascii_alphanumeric) || numbered("F", 1..=24).is_some() {
+        key.to_string()
+    } else if let Some(n) = numbered("NUMPAD", 0..=9).or(numbered("NUM", 0..=9)) {
+        format!("NUMPAD{}", n)
+    } else {
+        KEYS.iter()
+            .find(|(alias, _)| alias == key)
+            .map(|(_, key)| key.to_string())
+            .ok_or(if key.is_empty() {
+                format!("`{}` has no key", shortcut)
+            } else {
+                format!("`{}` in `{}` is not a known key", key, shortcut)
+            })?
+    };
+
+    normalized.push(key);
+    Ok(normalized.join("+"))
+}
+
+/// Whether `program` is a file, or can be found on the `PATH`
+fn binary_exists(program: &str) -> bool {
+    let path = Path::new(program);
+    if path.components().count() > 1 {
+        return path.exists();
+    }
+
+    let Some(paths) = env::var_os("PATH") else {
+        return false;
+    };
+    env::split_paths(&paths).any(|dir| {
+        let candidate = dir.join(program);
+        candidate.is_file() || (cfg!(target_os = "windows") && candidate.with_extension("exe").is_file())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_aliases_of_the_same_shortcut() {
+        let same = ["Command+Shift+Up", "shift+cmd+ArrowUp", "Super + Shift + UP"];
+        for shortcut in same {
+            assert_eq!(normalize_shortcut(shortcut).unwrap(), "COMMAND+SHIFT+ARROWUP");
+        }
+        assert_eq!(normalize_shortcut("Ctrl+KeyA").unwrap(), normalize_shortcut("Control+a").unwrap());
+        assert_eq!(normalize_shortcut("Alt+Digit1").unwrap(), normalize_shortcut("Option+1").unwrap());
+        assert_eq!(normalize_shortcut("Num0").unwrap(), normalize_shortcut("Numpad0").unwrap());
+        let spelled_out = if cfg!(target_os = "macos") { "Command+Shift+." } else { "Ctrl+Shift+." };
+        assert_eq!(normalize_shortcut("CmdOrCtrl+Shift+.").unwrap(), normalize_shortcut(spelled_out).unwrap());
+    }
+
+    #[test]
+    fn knows_the_keys_tauri_accepts() {
+        let keys = [
+            "F24", "Plus", "CapsLock", "NumLock", "Numpad9", "NumpadAdd", "PrintScreen", "ScrollLock",
+            "AudioVolumeUp", "VolumeMute", "MediaPlayPause", "MediaTrackPrev", "Escape", "`",
+        ];
+        for key in keys {
+            assert!(normalize_shortcut(&format!("Shift+{}", key)).is_ok(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn rejects_what_it_does_not_know() {
+        assert!(normalize_shortcut("Hyper+A").unwrap_err().contains("not a known modifier"));
+        assert!(normalize_shortcut("Shift+F25").unwrap_err().contains("not a known key"));
+        assert!(normalize_shortcut("Shift+").unwrap_err().contains("has no key"));
+    }
+}